@@ -0,0 +1,57 @@
+//! Compare the throughput of the two [`Scheduler`]s against a local mock
+//! server: `cargo run --release --example scheduler_benchmark`.
+
+use panels_downloader::{AppBuilder, Scheduler};
+use serde_json::json;
+use std::time::Instant;
+use wiremock::matchers::{method, path, path_regex};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const MANIFEST_PATH: &str = "/panels-api/data/20240916/media-1a-i-p~s";
+const WALLPAPERS: usize = 500;
+const WALLPAPER_BYTES: usize = 256 * 1024;
+const WORKERS: usize = 10;
+const ROUNDS: usize = 5;
+
+#[tokio::main]
+async fn main() {
+    let server = MockServer::start().await;
+    let data: serde_json::Map<_, _> = (0..WALLPAPERS)
+        .map(|i| {
+            let url = format!("{}/content/{}.jpg?fm=jpg", server.uri(), i);
+            (i.to_string(), json!({ "dhd": url }))
+        })
+        .collect();
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "version": 1, "data": data })),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0; WALLPAPER_BYTES]))
+        .mount(&server)
+        .await;
+
+    for scheduler in [Scheduler::JoinSet, Scheduler::FuturesUnordered] {
+        let mut total_secs = 0.0;
+        for _ in 0..ROUNDS {
+            let output = tempfile::tempdir().unwrap();
+            let app = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), WORKERS)
+                .scheduler(scheduler)
+                .build();
+            let started = Instant::now();
+            app.run().await.unwrap();
+            total_secs += started.elapsed().as_secs_f64();
+        }
+        let megabytes = (ROUNDS * WALLPAPERS * WALLPAPER_BYTES) as f64 / 1_000_000.0;
+        println!(
+            "{:?}: {:.1} MB/s over {} rounds",
+            scheduler,
+            megabytes / total_secs,
+            ROUNDS
+        );
+    }
+}
@@ -0,0 +1,2305 @@
+use crate::checkpoint::Checkpoint;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::continuation::DownloadContinuation;
+use crate::cookies;
+use crate::dedupe::ContentIndex;
+use crate::error::PanelsError;
+use crate::etag_cache::EtagCache;
+use crate::field_map::FieldMap;
+use crate::ignore_file::{read_ignored_keys, IGNORE_FILE_NAME};
+use crate::manifest::{
+    manifest_path, shard_url, ConflictResolution, Manifest, ManifestData, SortOrder,
+    DEFAULT_MANIFEST_SHARD, MANIFEST_DATE,
+};
+use crate::metrics::Metrics;
+use crate::pid_file::PidFile;
+use crate::progress::{Progress, ProgressEvent};
+use crate::rate_limit::{HostPermits, TokenBucket};
+use crate::retry::{HostRetryBudget, RetryPolicy, DEFAULT_RETRY_STATUS_CODES};
+use crate::robots::RobotsTxt;
+use crate::scaling::{WorkerScaling, WorkerTasks};
+use crate::self_test::{self, SelfTestStep};
+use crate::stats::DirectoryStats;
+use crate::status::StatusFile;
+use crate::summary::{serialize_secs, Summary};
+use crate::task::{
+    hash_directory, AdaptiveTimeout, DownloadStrategy, DownloadTask, Downloader, NamingStrategy,
+    OutputTemplate,
+};
+use crate::watch;
+#[cfg(feature = "exif")]
+use crate::xmp::{self, XmpTag};
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Local, NaiveDate};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    tls, Client, ClientBuilder, StatusCode, Url,
+};
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use serde::{Serialize, Serializer};
+use std::cmp::max;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fs::{create_dir_all, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    pin::pin,
+};
+use tokio::{
+    sync::{OnceCell, Semaphore},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// How the download workers of a run are driven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, clap::ValueEnum)]
+pub enum Scheduler {
+    /// Each worker is spawned as its own task on the runtime's thread pool.
+    #[default]
+    JoinSet,
+    /// Every worker is polled from the task running the download, so they
+    /// share one thread. A panicking worker takes the whole run down with it.
+    FuturesUnordered,
+}
+
+/// What a run does when a download fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, clap::ValueEnum)]
+pub enum ErrorStrategy {
+    /// Stop every worker at the first failed download and fail the run.
+    #[default]
+    FailFast,
+    /// Keep going and succeed, with the failures listed in the [`Summary`].
+    BestEffort,
+    /// Keep going, logging each failure, and only fail the run if no
+    /// download succeeded.
+    FailSlow,
+}
+
+/// The oldest TLS protocol version the client will negotiate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, clap::ValueEnum)]
+pub enum TlsVersion {
+    #[default]
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    V1_2,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    V1_3,
+}
+
+impl TlsVersion {
+    fn reqwest_version(self) -> tls::Version {
+        match self {
+            TlsVersion::V1_2 => tls::Version::TLS_1_2,
+            TlsVersion::V1_3 => tls::Version::TLS_1_3,
+        }
+    }
+}
+
+/// The IP protocol used to reach the panels server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    /// The wildcard address of this protocol. Binding to it forces the OS to
+    /// pick a route of this protocol only.
+    fn unspecified_address(self) -> IpAddr {
+        match self {
+            IpVersion::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpVersion::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct AppBuilder {
+    panels_domain: String,
+    download_directory: PathBuf,
+    workers: usize,
+    ip_version: Option<IpVersion>,
+    symlinks_directory: Option<PathBuf>,
+    metrics_port: Option<u16>,
+    cookie_file: Option<PathBuf>,
+    save_cookies: bool,
+    gzip_manifest: bool,
+    file_mode: Option<u32>,
+    circuit_breaker_threshold: Option<u32>,
+    #[serde(serialize_with = "serialize_secs")]
+    circuit_breaker_reset_timeout: Duration,
+    key_prefix: Option<String>,
+    #[cfg(feature = "regex-filter")]
+    #[serde(serialize_with = "serialize_regex")]
+    key_regex: Option<regex::Regex>,
+    manifest_url: Option<String>,
+    manifest_shards: Vec<String>,
+    shard_conflict: ConflictResolution,
+    pid_file: Option<PathBuf>,
+    category: Option<String>,
+    etag_cache_dir: Option<PathBuf>,
+    download_strategy: DownloadStrategy,
+    max_retries: u32,
+    #[serde(serialize_with = "serialize_sorted")]
+    retry_status_codes: HashSet<u16>,
+    latest_symlink: Option<PathBuf>,
+    date_subdirectory: bool,
+    rate_limit: Option<u64>,
+    naming_strategy: NamingStrategy,
+    parallel_writes: Option<usize>,
+    #[serde(serialize_with = "serialize_redacted")]
+    extra_headers: HashMap<String, String>,
+    #[serde(serialize_with = "serialize_optional_secs")]
+    pool_idle_timeout: Option<Duration>,
+    deduplicate: bool,
+    max_retries_per_host: Option<u32>,
+    #[serde(serialize_with = "serialize_optional_secs")]
+    timeout_per_mb: Option<Duration>,
+    #[serde(serialize_with = "serialize_secs")]
+    min_timeout: Duration,
+    field_map: FieldMap,
+    scheduler: Scheduler,
+    output_template: Option<OutputTemplate>,
+    import_curl_cookies: Option<PathBuf>,
+    max_connections_per_host: Option<usize>,
+    url_list: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+    animated: Option<bool>,
+    manifest_subset: Option<PathBuf>,
+    max_workers: Option<usize>,
+    #[serde(serialize_with = "serialize_secs")]
+    slow_download_threshold: Duration,
+    quality_order: Option<SortOrder>,
+    insecure_http: bool,
+    tls_min_version: TlsVersion,
+    status_file: Option<PathBuf>,
+    #[serde(serialize_with = "serialize_secs")]
+    status_interval: Duration,
+    #[cfg(feature = "native-tls")]
+    native_tls: bool,
+    manifest_max_retries: u32,
+    hash_directories: bool,
+    error_strategy: ErrorStrategy,
+    normalize_urls: bool,
+    #[serde(serialize_with = "serialize_optional_secs")]
+    connect_test_timeout: Option<Duration>,
+    stream_manifest: bool,
+    respect_robots_txt: bool,
+    #[serde(serialize_with = "serialize_optional_secs")]
+    total_timeout: Option<Duration>,
+    deduplicate_by_url: bool,
+    open_when_done: bool,
+    #[cfg(feature = "exif")]
+    xmp_tags: Vec<XmpTag>,
+    flush_writes: bool,
+}
+
+impl AppBuilder {
+    pub fn new(panels_domain: &str, download_directory: &str, workers: usize) -> Self {
+        Self {
+            panels_domain: String::from(panels_domain),
+            download_directory: PathBuf::from(download_directory),
+            workers,
+            ip_version: None,
+            symlinks_directory: None,
+            metrics_port: None,
+            cookie_file: None,
+            save_cookies: false,
+            gzip_manifest: false,
+            file_mode: None,
+            circuit_breaker_threshold: None,
+            circuit_breaker_reset_timeout: Duration::from_secs(30),
+            key_prefix: None,
+            #[cfg(feature = "regex-filter")]
+            key_regex: None,
+            manifest_url: None,
+            manifest_shards: Vec::new(),
+            shard_conflict: ConflictResolution::Error,
+            pid_file: None,
+            category: None,
+            etag_cache_dir: None,
+            download_strategy: DownloadStrategy::HdOnly,
+            max_retries: 3,
+            retry_status_codes: HashSet::from(DEFAULT_RETRY_STATUS_CODES),
+            latest_symlink: None,
+            date_subdirectory: false,
+            rate_limit: None,
+            naming_strategy: NamingStrategy::ManifestKey,
+            parallel_writes: None,
+            extra_headers: HashMap::new(),
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            deduplicate: false,
+            max_retries_per_host: None,
+            timeout_per_mb: None,
+            min_timeout: Duration::from_secs(30),
+            field_map: FieldMap::default(),
+            scheduler: Scheduler::default(),
+            output_template: None,
+            import_curl_cookies: None,
+            max_connections_per_host: None,
+            url_list: None,
+            checkpoint: None,
+            animated: None,
+            manifest_subset: None,
+            max_workers: None,
+            slow_download_threshold: Duration::from_secs(10),
+            quality_order: None,
+            insecure_http: false,
+            tls_min_version: TlsVersion::default(),
+            status_file: None,
+            status_interval: Duration::from_secs(5),
+            #[cfg(feature = "native-tls")]
+            native_tls: false,
+            manifest_max_retries: 5,
+            hash_directories: false,
+            error_strategy: ErrorStrategy::default(),
+            normalize_urls: false,
+            connect_test_timeout: None,
+            stream_manifest: false,
+            respect_robots_txt: false,
+            total_timeout: None,
+            deduplicate_by_url: true,
+            open_when_done: false,
+            #[cfg(feature = "exif")]
+            xmp_tags: Vec::new(),
+            flush_writes: true,
+        }
+    }
+
+    /// Restrict connections to a single IP protocol. `None` lets the OS decide.
+    pub fn ip_version(mut self, ip_version: Option<IpVersion>) -> Self {
+        self.ip_version = ip_version;
+        self
+    }
+
+    /// Also link every downloaded wallpaper into this directory as `<key>.jpg`.
+    pub fn symlinks_directory(mut self, symlinks_directory: Option<PathBuf>) -> Self {
+        self.symlinks_directory = symlinks_directory;
+        self
+    }
+
+    /// Serve Prometheus metrics on this port while the app runs.
+    pub fn metrics_port(mut self, metrics_port: Option<u16>) -> Self {
+        self.metrics_port = metrics_port;
+        self
+    }
+
+    /// Send the cookies stored in this Netscape cookie jar, the format
+    /// `curl --cookie-jar` writes, with every request.
+    pub fn cookie_file(mut self, cookie_file: Option<PathBuf>) -> Self {
+        self.cookie_file = cookie_file;
+        self
+    }
+
+    /// Write the cookie jar back to the cookie file once the run finishes.
+    pub fn save_cookies(mut self, save_cookies: bool) -> Self {
+        self.save_cookies = save_cookies;
+        self
+    }
+
+    /// Send `Accept-Encoding: gzip` with the manifest request and transparently
+    /// decompress the answer. Only useful when the server honours the header
+    /// and answers with `Content-Encoding: gzip`; wallpapers are never
+    /// requested compressed.
+    pub fn gzip_manifest(mut self, gzip_manifest: bool) -> Self {
+        self.gzip_manifest = gzip_manifest;
+        self
+    }
+
+    /// Unix permission bits, e.g. `0o644`, applied to every downloaded
+    /// wallpaper. Ignored on other platforms.
+    pub fn file_mode(mut self, file_mode: Option<u32>) -> Self {
+        self.file_mode = file_mode;
+        self
+    }
+
+    /// Skip the remaining downloads once this many downloads in a row have
+    /// failed, until the circuit breaker reset timeout lets a probe through.
+    pub fn circuit_breaker_threshold(mut self, circuit_breaker_threshold: Option<u32>) -> Self {
+        self.circuit_breaker_threshold = circuit_breaker_threshold;
+        self
+    }
+
+    /// How long an open circuit breaker waits before probing the server again.
+    pub fn circuit_breaker_reset_timeout(
+        mut self,
+        circuit_breaker_reset_timeout: Duration,
+    ) -> Self {
+        self.circuit_breaker_reset_timeout = circuit_breaker_reset_timeout;
+        self
+    }
+
+    /// Only download wallpapers whose manifest key starts with this prefix.
+    pub fn key_prefix(mut self, key_prefix: Option<String>) -> Self {
+        self.key_prefix = key_prefix;
+        self
+    }
+
+    /// Only download wallpapers whose manifest key matches this pattern.
+    #[cfg(feature = "regex-filter")]
+    pub fn key_regex(mut self, key_regex: Option<regex::Regex>) -> Self {
+        self.key_regex = key_regex;
+        self
+    }
+
+    /// Fetch the manifest from this URL instead of the built-in dated path,
+    /// e.g. the path [`App::latest_manifest_path`] finds. A path is taken
+    /// relative to the panels domain.
+    pub fn manifest_url(mut self, manifest_url: Option<String>) -> Self {
+        self.manifest_url = manifest_url;
+        self
+    }
+
+    /// Fetch these manifest shards (e.g. `media-1a`, `media-1b`) concurrently
+    /// and merge them instead of the single default manifest.
+    pub fn manifest_shards(mut self, manifest_shards: Vec<String>) -> Self {
+        self.manifest_shards = manifest_shards;
+        self
+    }
+
+    /// What to do when two manifest shards contain the same key.
+    pub fn shard_conflict(mut self, shard_conflict: ConflictResolution) -> Self {
+        self.shard_conflict = shard_conflict;
+        self
+    }
+
+    /// Hold an advisory lock on this file, containing our pid, for the whole
+    /// run so a second instance cannot write into the same output directory.
+    pub fn pid_file(mut self, pid_file: Option<PathBuf>) -> Self {
+        self.pid_file = pid_file;
+        self
+    }
+
+    /// Only download wallpapers whose [`ManifestData::category`] is this value.
+    pub fn category(mut self, category: Option<String>) -> Self {
+        self.category = category;
+        self
+    }
+
+    /// Remember each wallpaper's `ETag` in this directory and skip wallpapers the
+    /// server reports as unchanged on later runs.
+    pub fn etag_cache_dir(mut self, etag_cache_dir: Option<PathBuf>) -> Self {
+        self.etag_cache_dir = etag_cache_dir;
+        self
+    }
+
+    /// Which URLs of each manifest entry to download. Defaults to the wallpaper
+    /// itself.
+    pub fn download_strategy(mut self, download_strategy: DownloadStrategy) -> Self {
+        self.download_strategy = download_strategy;
+        self
+    }
+
+    /// How many times a failed request is retried, with exponential backoff.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// HTTP statuses that are retried like network errors. Defaults to
+    /// [`DEFAULT_RETRY_STATUS_CODES`].
+    pub fn retry_status_codes(mut self, retry_status_codes: HashSet<u16>) -> Self {
+        self.retry_status_codes = retry_status_codes;
+        self
+    }
+
+    /// After a successful run, point this symlink at the output directory.
+    pub fn latest_symlink(mut self, latest_symlink: Option<PathBuf>) -> Self {
+        self.latest_symlink = latest_symlink;
+        self
+    }
+
+    /// Download into a `YYYY-MM-DD` subdirectory of the output directory, named
+    /// after the local date the run started.
+    pub fn date_subdirectory(mut self, date_subdirectory: bool) -> Self {
+        self.date_subdirectory = date_subdirectory;
+        self
+    }
+
+    /// Cap every worker at this many bytes per second.
+    pub fn rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// How downloaded files are named. Defaults to the manifest key.
+    pub fn naming_strategy(mut self, naming_strategy: NamingStrategy) -> Self {
+        self.naming_strategy = naming_strategy;
+        self
+    }
+
+    /// Move file writes onto tokio's blocking thread pool, with at most this many
+    /// running at once across all workers. By default writes happen on the worker
+    /// tasks.
+    pub fn parallel_writes(mut self, parallel_writes: Option<usize>) -> Self {
+        self.parallel_writes = parallel_writes;
+        self
+    }
+
+    /// Send these headers with every request, e.g. the ones
+    /// [`ExtraHeaders::from_curl_command`](crate::ExtraHeaders::from_curl_command) extracts.
+    pub fn extra_headers(mut self, extra_headers: HashMap<String, String>) -> Self {
+        self.extra_headers = extra_headers;
+        self
+    }
+
+    /// Close pooled connections that have been idle this long, so a long pause
+    /// does not leave the next request on a connection the server already
+    /// dropped. `None` keeps them open indefinitely.
+    pub fn pool_idle_timeout(mut self, pool_idle_timeout: Option<Duration>) -> Self {
+        self.pool_idle_timeout = pool_idle_timeout;
+        self
+    }
+
+    /// Hash every downloaded file with SHA-256 and replace files whose content
+    /// was already downloaded under another key with hard links to the first
+    /// copy. Off by default, since every byte has to be hashed.
+    pub fn deduplicate(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    /// Fail every remaining request to a host once this many retries have been
+    /// made to it in total, so a server that is down does not keep every download
+    /// retrying.
+    pub fn max_retries_per_host(mut self, max_retries_per_host: Option<u32>) -> Self {
+        self.max_retries_per_host = max_retries_per_host;
+        self
+    }
+
+    /// Give each download this long per megabyte of its `Content-Length` to
+    /// arrive, but at least the [`min_timeout`](Self::min_timeout). Downloads
+    /// of unknown size are not timed out.
+    pub fn timeout_per_mb(mut self, timeout_per_mb: Option<Duration>) -> Self {
+        self.timeout_per_mb = timeout_per_mb;
+        self
+    }
+
+    /// The shortest time limit [`timeout_per_mb`](Self::timeout_per_mb) gives
+    /// a download, however small.
+    pub fn min_timeout(mut self, min_timeout: Duration) -> Self {
+        self.min_timeout = min_timeout;
+        self
+    }
+
+    /// Rename the fields of fetched manifest entries before they are read, for
+    /// servers that do not use the standard field names.
+    pub fn field_map(mut self, field_map: FieldMap) -> Self {
+        self.field_map = field_map;
+        self
+    }
+
+    /// How the download workers are driven; see [`Scheduler`].
+    pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Lay the files out under the output directory by an [`OutputTemplate`],
+    /// instead of saving them side by side under their names.
+    pub fn output_template(mut self, output_template: Option<OutputTemplate>) -> Self {
+        self.output_template = output_template;
+        self
+    }
+
+    /// Send the cookies of a Netscape `cookies.txt` jar, as written by curl and
+    /// wget, with every request. Unlike the cookie file, it is never written.
+    pub fn import_curl_cookies(mut self, import_curl_cookies: Option<PathBuf>) -> Self {
+        self.import_curl_cookies = import_curl_cookies;
+        self
+    }
+
+    /// Run at most this many downloads from one host at a time across all
+    /// workers, for servers that limit connections per client. Manifest and
+    /// size requests are not counted.
+    pub fn max_connections_per_host(mut self, max_connections_per_host: Option<usize>) -> Self {
+        self.max_connections_per_host = max_connections_per_host;
+        self
+    }
+
+    /// Before downloading, write the URL of every file the run will download to
+    /// this file, one per line, e.g. for `aria2c -i`.
+    pub fn url_list(mut self, url_list: Option<PathBuf>) -> Self {
+        self.url_list = url_list;
+        self
+    }
+
+    /// Only download keys missing from this file of previously downloaded keys,
+    /// one per line, and append the keys downloaded by the run to it.
+    pub fn checkpoint(mut self, checkpoint: Option<PathBuf>) -> Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// With `Some(true)` only download entries [`ManifestData::is_animated`]
+    /// guesses are animated, with `Some(false)` skip them.
+    pub fn animated(mut self, animated: Option<bool>) -> Self {
+        self.animated = animated;
+        self
+    }
+
+    /// After the run, save a manifest holding only the entries that were
+    /// downloaded to this file. Failed and skipped entries are left out.
+    pub fn manifest_subset(mut self, manifest_subset: Option<PathBuf>) -> Self {
+        self.manifest_subset = manifest_subset;
+        self
+    }
+
+    /// Experimental: let the number of workers grow up to this many while
+    /// downloads take longer than the slow download threshold on average, and
+    /// shrink back to `workers` once they are fast again.
+    pub fn max_workers(mut self, max_workers: Option<usize>) -> Self {
+        self.max_workers = max_workers;
+        self
+    }
+
+    /// The average download time above which adaptive workers are added.
+    pub fn slow_download_threshold(mut self, slow_download_threshold: Duration) -> Self {
+        self.slow_download_threshold = slow_download_threshold;
+        self
+    }
+
+    /// Download entries in this order of [`ManifestData::preferred_quality_tier`],
+    /// with `Desc` starting from the best, instead of in key order.
+    pub fn quality_order(mut self, quality_order: Option<SortOrder>) -> Self {
+        self.quality_order = quality_order;
+        self
+    }
+
+    /// Reach a domain given without a scheme over plain `http://` instead of
+    /// `https://`. An explicit `http://` domain without this setting still works
+    /// but logs a deprecation warning.
+    pub fn insecure_http(mut self, insecure_http: bool) -> Self {
+        self.insecure_http = insecure_http;
+        self
+    }
+
+    /// Refuse to connect over TLS versions older than this one.
+    pub fn tls_min_version(mut self, tls_min_version: TlsVersion) -> Self {
+        self.tls_min_version = tls_min_version;
+        self
+    }
+
+    /// Keep a JSON file here up to date with the progress of the run, for
+    /// monitoring tools to poll.
+    pub fn status_file(mut self, status_file: Option<PathBuf>) -> Self {
+        self.status_file = status_file;
+        self
+    }
+
+    /// How often the status file is rewritten.
+    pub fn status_interval(mut self, status_interval: Duration) -> Self {
+        self.status_interval = status_interval;
+        self
+    }
+
+    /// Connect through the platform TLS library instead of rustls.
+    #[cfg(feature = "native-tls")]
+    pub fn native_tls(mut self, native_tls: bool) -> Self {
+        self.native_tls = native_tls;
+        self
+    }
+
+    /// Retry a failed manifest fetch this many times, with the same backoff as
+    /// downloads. Separate from `max_retries`, since the manifest is cheap to
+    /// fetch again and the run cannot start without it.
+    pub fn manifest_max_retries(mut self, manifest_max_retries: u32) -> Self {
+        self.manifest_max_retries = manifest_max_retries;
+        self
+    }
+
+    /// Put each file two directories deep, named after the SHA-256 of its key,
+    /// e.g. `ab/cd/key.jpg`, so that no one directory holds thousands of files.
+    pub fn hash_directories(mut self, hash_directories: bool) -> Self {
+        self.hash_directories = hash_directories;
+        self
+    }
+
+    /// What the run does when a download fails.
+    pub fn error_strategy(mut self, error_strategy: ErrorStrategy) -> Self {
+        self.error_strategy = error_strategy;
+        self
+    }
+
+    /// Strip query strings and fragments from the manifest URLs after fetching
+    /// it, see [`ManifestData::normalize_urls`].
+    pub fn normalize_urls(mut self, normalize_urls: bool) -> Self {
+        self.normalize_urls = normalize_urls;
+        self
+    }
+
+    /// Check that the server accepts a TCP connection within this long before
+    /// making any HTTP request.
+    pub fn connect_test_timeout(mut self, connect_test_timeout: Option<Duration>) -> Self {
+        self.connect_test_timeout = connect_test_timeout;
+        self
+    }
+
+    /// Parse the manifest while it downloads, see [`Manifest::get_via_stream`].
+    /// Entries are filtered as they arrive, so those that are not downloaded
+    /// are never held in memory.
+    pub fn stream_manifest(mut self, stream_manifest: bool) -> Self {
+        self.stream_manifest = stream_manifest;
+        self
+    }
+
+    /// Check the manifest and wallpaper URLs on the panels domain against its
+    /// robots.txt before requesting them.
+    pub fn respect_robots_txt(mut self, respect_robots_txt: bool) -> Self {
+        self.respect_robots_txt = respect_robots_txt;
+        self
+    }
+
+    /// A deadline for downloading every wallpaper of a run, after which the
+    /// downloads in flight are cancelled and
+    /// [`PanelsError::TotalTimeoutExceeded`] returned.
+    pub fn total_timeout(mut self, total_timeout: Option<Duration>) -> Self {
+        self.total_timeout = total_timeout;
+        self
+    }
+
+    /// Only download a URL once when it is listed under several keys, under
+    /// the first of them in key order. On by default. It costs nothing at
+    /// download time, unlike [`deduplicate`](Self::deduplicate), which also
+    /// catches the same content served from different URLs.
+    pub fn deduplicate_by_url(mut self, deduplicate_by_url: bool) -> Self {
+        self.deduplicate_by_url = deduplicate_by_url;
+        self
+    }
+
+    /// Open the output directory in the system file manager after a successful
+    /// run. Failing to open it is only logged, since the downloads are done.
+    pub fn open_when_done(mut self, open_when_done: bool) -> Self {
+        self.open_when_done = open_when_done;
+        self
+    }
+
+    /// Write these properties into the XMP metadata of every downloaded JPEG.
+    #[cfg(feature = "exif")]
+    pub fn xmp_tags(mut self, xmp_tags: Vec<XmpTag>) -> Self {
+        self.xmp_tags = xmp_tags;
+        self
+    }
+
+    /// Wait for the last write of each download to reach the file before
+    /// moving on, and report it if it fails. On by default. Without it the
+    /// write finishes in the background, which saves a little time per file
+    /// but loses its errors, so a full disk or a crash can leave a file
+    /// truncated even though the download counts as complete.
+    pub fn flush_writes(mut self, flush_writes: bool) -> Self {
+        self.flush_writes = flush_writes;
+        self
+    }
+
+    /// Check every setting up front and report all the problems at once as a
+    /// [`PanelsError::InvalidConfiguration`], rather than failing mid-run or
+    /// quietly correcting them in [`build`](Self::build).
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        if self.workers == 0 {
+            errors.push(String::from("workers must be at least 1"));
+        }
+        if self.panels_domain.trim().is_empty() {
+            errors.push(String::from("the panels domain must not be empty"));
+        }
+        if self.download_directory.as_os_str().is_empty() {
+            errors.push(String::from("the output directory must not be empty"));
+        }
+        if let Some(character) = invalid_path_character(&self.download_directory) {
+            errors.push(format!(
+                "the output directory {} contains the invalid character {:?}",
+                self.download_directory.display(),
+                character
+            ));
+        }
+        if self.save_cookies && self.cookie_file.is_none() {
+            errors.push(String::from("saving cookies requires a cookie file"));
+        }
+        if let Some(mode) = self.file_mode.filter(|mode| *mode > 0o7777) {
+            errors.push(format!("file mode {:o} has bits set outside 7777", mode));
+        }
+        if self
+            .max_workers
+            .is_some_and(|max_workers| max_workers < self.workers)
+        {
+            errors.push(String::from(
+                "the maximum number of adaptive workers must not be below the number of workers",
+            ));
+        }
+        if self.max_connections_per_host == Some(0) {
+            errors.push(String::from("the connections per host must be at least 1"));
+        }
+        #[cfg(feature = "native-tls")]
+        if self.native_tls && self.tls_min_version == TlsVersion::V1_3 {
+            errors.push(String::from(
+                "the native TLS backend cannot require TLS 1.3, use rustls instead",
+            ));
+        }
+        if self.total_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            errors.push(String::from("the total timeout must be longer than zero"));
+        }
+        if self.status_interval.is_zero() {
+            errors.push(String::from("the status interval must be longer than zero"));
+        }
+        if self.parallel_writes == Some(0) {
+            errors.push(String::from("parallel writes must be at least 1"));
+        }
+        if self.rate_limit == Some(0) {
+            errors.push(String::from(
+                "the rate limit must be at least 1 byte per second",
+            ));
+        }
+        if self.circuit_breaker_threshold == Some(0) {
+            errors.push(String::from(
+                "the circuit breaker threshold must be at least 1",
+            ));
+        }
+        if self
+            .manifest_shards
+            .iter()
+            .any(|shard| shard.trim().is_empty())
+        {
+            errors.push(String::from("manifest shard names must not be empty"));
+        }
+        if let Some(url) = &self.manifest_url {
+            if !self.manifest_shards.is_empty() {
+                errors.push(String::from(
+                    "a manifest URL cannot be combined with manifest shards",
+                ));
+            }
+            if !url.starts_with('/') && Url::parse(url).is_err() {
+                errors.push(String::from(
+                    "the manifest URL must be a path or an absolute URL",
+                ));
+            }
+        }
+        if let NamingStrategy::Template(template) = &self.naming_strategy {
+            if !template.contains("{key}") && !template.contains("{basename}") {
+                errors.push(String::from(
+                    "the name template must contain {key} or {basename}",
+                ));
+            }
+            if template.contains(['/', '\\']) {
+                errors.push(String::from(
+                    "the name template must not contain path separators",
+                ));
+            }
+        }
+        if let Some(template) = &self.output_template {
+            errors.extend(template.problems());
+        }
+        #[cfg(feature = "exif")]
+        if !self.flush_writes && !self.xmp_tags.is_empty() {
+            errors.push(String::from(
+                "tagging files needs their writes flushed, so it cannot skip flushing",
+            ));
+        }
+        if self.stream_manifest && !self.field_map.is_empty() {
+            errors.push(String::from(
+                "a streamed manifest cannot have its fields renamed",
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(PanelsError::InvalidConfiguration(errors).into())
+        }
+    }
+
+    pub fn build(self) -> App {
+        App {
+            panels_domain: resolve_domain(&self.panels_domain, self.insecure_http),
+            download_directory: self.download_directory,
+            workers: max(self.workers, 1),
+            ip_version: self.ip_version,
+            symlinks_directory: self.symlinks_directory,
+            metrics_port: self.metrics_port,
+            cookie_file: self.cookie_file,
+            save_cookies: self.save_cookies,
+            gzip_manifest: self.gzip_manifest,
+            file_mode: self.file_mode,
+            circuit_breaker_threshold: self.circuit_breaker_threshold,
+            circuit_breaker_reset_timeout: self.circuit_breaker_reset_timeout,
+            key_prefix: self.key_prefix,
+            #[cfg(feature = "regex-filter")]
+            key_regex: self.key_regex,
+            manifest_url: self.manifest_url,
+            manifest_shards: self.manifest_shards,
+            shard_conflict: self.shard_conflict,
+            pid_file: self.pid_file,
+            category: self.category,
+            etag_cache_dir: self.etag_cache_dir,
+            download_strategy: self.download_strategy,
+            max_retries: self.max_retries,
+            retry_status_codes: self.retry_status_codes,
+            latest_symlink: self.latest_symlink,
+            date_subdirectory: self.date_subdirectory,
+            rate_limit: self.rate_limit,
+            naming_strategy: self.naming_strategy,
+            parallel_writes: self.parallel_writes,
+            extra_headers: self.extra_headers,
+            pool_idle_timeout: self.pool_idle_timeout,
+            deduplicate: self.deduplicate,
+            max_retries_per_host: self.max_retries_per_host,
+            timeout_per_mb: self.timeout_per_mb,
+            min_timeout: self.min_timeout,
+            field_map: self.field_map,
+            scheduler: self.scheduler,
+            output_template: self.output_template,
+            import_curl_cookies: self.import_curl_cookies,
+            max_connections_per_host: self.max_connections_per_host,
+            url_list: self.url_list,
+            checkpoint: self.checkpoint,
+            animated: self.animated,
+            manifest_subset: self.manifest_subset,
+            max_workers: self.max_workers,
+            slow_download_threshold: self.slow_download_threshold,
+            quality_order: self.quality_order,
+            tls_min_version: self.tls_min_version,
+            status_file: self.status_file,
+            status_interval: self.status_interval,
+            #[cfg(feature = "native-tls")]
+            native_tls: self.native_tls,
+            manifest_max_retries: self.manifest_max_retries,
+            hash_directories: self.hash_directories,
+            error_strategy: self.error_strategy,
+            normalize_urls: self.normalize_urls,
+            connect_test_timeout: self.connect_test_timeout,
+            stream_manifest: self.stream_manifest,
+            respect_robots_txt: self.respect_robots_txt,
+            total_timeout: self.total_timeout,
+            deduplicate_by_url: self.deduplicate_by_url,
+            open_when_done: self.open_when_done,
+            #[cfg(feature = "exif")]
+            xmp_tags: self.xmp_tags,
+            flush_writes: self.flush_writes,
+            progress: Progress::default(),
+            metrics: Arc::default(),
+            cookie_store: Arc::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct App {
+    panels_domain: String,
+    download_directory: PathBuf,
+    workers: usize,
+    ip_version: Option<IpVersion>,
+    symlinks_directory: Option<PathBuf>,
+    metrics_port: Option<u16>,
+    cookie_file: Option<PathBuf>,
+    save_cookies: bool,
+    gzip_manifest: bool,
+    file_mode: Option<u32>,
+    circuit_breaker_threshold: Option<u32>,
+    circuit_breaker_reset_timeout: Duration,
+    key_prefix: Option<String>,
+    #[cfg(feature = "regex-filter")]
+    key_regex: Option<regex::Regex>,
+    manifest_url: Option<String>,
+    manifest_shards: Vec<String>,
+    shard_conflict: ConflictResolution,
+    pid_file: Option<PathBuf>,
+    category: Option<String>,
+    etag_cache_dir: Option<PathBuf>,
+    download_strategy: DownloadStrategy,
+    max_retries: u32,
+    retry_status_codes: HashSet<u16>,
+    latest_symlink: Option<PathBuf>,
+    date_subdirectory: bool,
+    rate_limit: Option<u64>,
+    naming_strategy: NamingStrategy,
+    parallel_writes: Option<usize>,
+    extra_headers: HashMap<String, String>,
+    pool_idle_timeout: Option<Duration>,
+    deduplicate: bool,
+    max_retries_per_host: Option<u32>,
+    timeout_per_mb: Option<Duration>,
+    min_timeout: Duration,
+    field_map: FieldMap,
+    scheduler: Scheduler,
+    output_template: Option<OutputTemplate>,
+    import_curl_cookies: Option<PathBuf>,
+    max_connections_per_host: Option<usize>,
+    url_list: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+    animated: Option<bool>,
+    manifest_subset: Option<PathBuf>,
+    max_workers: Option<usize>,
+    slow_download_threshold: Duration,
+    quality_order: Option<SortOrder>,
+    tls_min_version: TlsVersion,
+    status_file: Option<PathBuf>,
+    status_interval: Duration,
+    #[cfg(feature = "native-tls")]
+    native_tls: bool,
+    manifest_max_retries: u32,
+    hash_directories: bool,
+    error_strategy: ErrorStrategy,
+    normalize_urls: bool,
+    connect_test_timeout: Option<Duration>,
+    stream_manifest: bool,
+    respect_robots_txt: bool,
+    total_timeout: Option<Duration>,
+    deduplicate_by_url: bool,
+    open_when_done: bool,
+    #[cfg(feature = "exif")]
+    xmp_tags: Vec<XmpTag>,
+    flush_writes: bool,
+    progress: Progress,
+    /// Created, and served if a metrics port is set, by the first run, so
+    /// later runs of the same app keep counting and reuse the server.
+    metrics: Arc<OnceCell<Arc<Metrics>>>,
+    /// See [`load_cookie_store`](Self::load_cookie_store).
+    cookie_store: Arc<OnceLock<Option<Arc<CookieStoreMutex>>>>,
+}
+
+impl App {
+    pub fn new(panels_domain: &str, download_directory: &str, workers: usize) -> Self {
+        AppBuilder::new(panels_domain, download_directory, workers).build()
+    }
+
+    pub fn builder(panels_domain: &str, download_directory: &str, workers: usize) -> AppBuilder {
+        AppBuilder::new(panels_domain, download_directory, workers)
+    }
+
+    /// Receive a [`ProgressEvent`] for every step of the run, e.g. to drive a
+    /// GUI. The callback is invoked from the worker tasks.
+    pub fn set_progress_callback(&mut self, cb: impl Fn(ProgressEvent) + Send + Sync + 'static) {
+        self.progress = Progress::new(Arc::new(cb));
+    }
+
+    /// A client builder on the configured TLS backend and minimum version,
+    /// bound to the configured IP version.
+    fn client_builder(&self) -> ClientBuilder {
+        // rustls behaves the same on every platform and needs no system
+        // OpenSSL. The platform library follows system-wide TLS policy and
+        // certificate stores, but cannot be limited to TLS 1.3.
+        #[cfg(feature = "native-tls")]
+        let builder = if self.native_tls {
+            Client::builder().use_native_tls()
+        } else {
+            Client::builder().use_rustls_tls()
+        };
+        #[cfg(not(feature = "native-tls"))]
+        let builder = Client::builder().use_rustls_tls();
+        let builder = builder.min_tls_version(self.tls_min_version.reqwest_version());
+        match self.ip_version {
+            Some(ip_version) => builder.local_address(ip_version.unspecified_address()),
+            None => builder,
+        }
+    }
+
+    fn client(&self, cookie_store: Option<Arc<CookieStoreMutex>>) -> Result<Client> {
+        let mut builder = self
+            .client_builder()
+            .gzip(false)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .default_headers(self.default_headers()?);
+        // Only caps the idle connections kept per host, so the pool holds no
+        // more than the downloads can use. The downloads themselves are
+        // limited by the host permits of the run, see `Downloader`.
+        if let Some(limit) = self.max_connections_per_host {
+            builder = builder.pool_max_idle_per_host(limit);
+        }
+        if let Some(cookie_store) = cookie_store {
+            builder = builder.cookie_provider(cookie_store);
+        }
+        builder.build().context("Failed to build the HTTP client")
+    }
+
+    fn default_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.extra_headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("`{}` is not a valid header name", name))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("The value of header `{}` is not valid", name))?;
+            headers.insert(name, value);
+        }
+        Ok(headers)
+    }
+
+    /// The `--cookie-file` jar, with the cookies of an imported cookies.txt
+    /// added on top so that saving the jar keeps them. Loaded by the first
+    /// call and shared by every client after it, so a cookie the manifest
+    /// request sets is sent with the downloads too.
+    fn load_cookie_store(&self) -> Result<Option<Arc<CookieStoreMutex>>> {
+        if let Some(store) = self.cookie_store.get() {
+            return Ok(store.clone());
+        }
+        let store = self.read_cookie_store()?;
+        Ok(self.cookie_store.get_or_init(|| store).clone())
+    }
+
+    fn read_cookie_store(&self) -> Result<Option<Arc<CookieStoreMutex>>> {
+        let mut store = match &self.cookie_file {
+            Some(path) => cookies::load_cookie_store(path)?,
+            None if self.import_curl_cookies.is_some() => CookieStore::default(),
+            None => return Ok(None),
+        };
+        if let Some(path) = &self.import_curl_cookies {
+            let contents =
+                std::fs::read_to_string(path).context("Failed to read the cookies.txt file")?;
+            cookies::insert_netscape_cookies(&mut store, &contents)?;
+        }
+        Ok(Some(Arc::new(CookieStoreMutex::new(store))))
+    }
+
+    /// Where this run saves its files. The date is taken once, up front, so a
+    /// run that crosses midnight stays in one directory.
+    fn run_directory(&self) -> PathBuf {
+        if self.date_subdirectory {
+            let today = Local::now().format("%Y-%m-%d").to_string();
+            self.download_directory.join(today)
+        } else {
+            self.download_directory.clone()
+        }
+    }
+
+    /// The download counters of every run of this app, serving them on the
+    /// metrics port from the first call on.
+    async fn metrics(&self) -> Result<&Arc<Metrics>> {
+        self.metrics
+            .get_or_try_init(|| async {
+                let metrics = Arc::new(Metrics::new()?);
+                if let Some(port) = self.metrics_port {
+                    metrics.serve(port).await?;
+                }
+                Ok(metrics)
+            })
+            .await
+    }
+
+    /// The settings shared by the downloads of one run into `download_dir`.
+    fn downloader(&self, client: Client, download_dir: PathBuf) -> Downloader {
+        Downloader {
+            client,
+            download_dir,
+            retry_policy: Arc::new(self.retry_policy()),
+            etag_cache: self.etag_cache_dir.clone().map(EtagCache::new),
+            write_pool: self
+                .parallel_writes
+                .map(|writes| Arc::new(Semaphore::new(writes))),
+            content_index: self.deduplicate.then(|| Arc::new(ContentIndex::default())),
+            timeout: self.timeout_per_mb.map(|per_mb| AdaptiveTimeout {
+                per_mb,
+                min: self.min_timeout,
+            }),
+            host_permits: self
+                .max_connections_per_host
+                .map(|limit| Arc::new(HostPermits::new(limit))),
+            cancel: CancellationToken::new(),
+            flush: self.flush_writes,
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            status_codes: self.retry_status_codes.clone(),
+            host_budget: self
+                .max_retries_per_host
+                .map(|limit| Arc::new(HostRetryBudget::new(limit))),
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Like [`retry_policy`](Self::retry_policy), but with the manifest's own
+    /// retry count and no share in the per-host budget of the downloads.
+    fn manifest_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.manifest_max_retries,
+            host_budget: None,
+            ..self.retry_policy()
+        }
+    }
+
+    /// Fetch the manifest the way [`run`](Self::run) would, merging shards
+    /// if any are configured. A streamed manifest only holds the entries
+    /// that pass the filters.
+    pub async fn fetch_manifest(&self) -> Result<Manifest> {
+        if let Some(timeout) = self.connect_test_timeout {
+            self.connect_test(timeout).await?;
+        }
+        let mut manifest = self.fetch_shards().await?;
+        if self.normalize_urls {
+            manifest.normalize_urls();
+        }
+        Ok(manifest)
+    }
+
+    /// Fail fast with [`PanelsError::ServerUnreachable`] when the server does
+    /// not accept a TCP connection within `timeout`, rather than waiting for
+    /// HTTP timeouts in every worker.
+    async fn connect_test(&self, timeout: Duration) -> Result<()> {
+        let address = async {
+            let addresses = self.resolve().await?;
+            self_test::connect(&addresses, timeout).await
+        }
+        .await
+        .context(PanelsError::ServerUnreachable(self.panels_domain.clone()))?;
+        debug!("Connected to {} in the pre-flight test", address);
+        Ok(())
+    }
+
+    /// The addresses the panels domain resolves to, limited to those of the
+    /// configured IP version.
+    async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        let addresses = self_test::resolve(&self.panels_domain).await?;
+        Ok(match self.ip_version {
+            Some(IpVersion::V4) => addresses.into_iter().filter(|a| a.is_ipv4()).collect(),
+            Some(IpVersion::V6) => addresses.into_iter().filter(|a| a.is_ipv6()).collect(),
+            None => addresses,
+        })
+    }
+
+    /// The robots.txt of the panels domain, matched for the `User-Agent` of
+    /// the extra headers, or the name of this crate without one.
+    async fn robots_txt(&self, client: &Client) -> Result<RobotsTxt> {
+        let user_agent = self
+            .extra_headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("user-agent"))
+            .map_or(env!("CARGO_PKG_NAME"), |(_, value)| value);
+        RobotsTxt::fetch(&self.panels_domain, client, user_agent).await
+    }
+
+    /// The client manifest requests are sent with, which unlike downloads
+    /// may ask for gzip. It shares the downloads' cookie jar.
+    fn manifest_client(&self) -> Result<Client> {
+        let mut builder = self
+            .client_builder()
+            .gzip(self.gzip_manifest)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .default_headers(self.default_headers()?);
+        if let Some(cookie_store) = self.load_cookie_store()? {
+            builder = builder.cookie_provider(cookie_store);
+        }
+        builder.build().context("Failed to build the HTTP client")
+    }
+
+    /// The path of the most recent manifest the server has, trying the data
+    /// directories of today and each of the seven days before it, newest
+    /// first, and then the built-in [`MANIFEST_DATE`]. `None` when none of them
+    /// answers `200 OK`.
+    pub async fn latest_manifest_path(&self) -> Result<Option<String>> {
+        let client = self.manifest_client()?;
+        let today = Local::now().date_naive();
+        let mut dates: Vec<_> = (0..=7)
+            .map(|days| {
+                (today - chrono::Days::new(days))
+                    .format("%Y%m%d")
+                    .to_string()
+            })
+            .collect();
+        if !dates.iter().any(|date| date == MANIFEST_DATE) {
+            dates.push(MANIFEST_DATE.to_string());
+        }
+        for date in dates {
+            let path = manifest_path(&date, DEFAULT_MANIFEST_SHARD);
+            let response = client
+                .get(format!("{}{}", self.panels_domain, path))
+                .send()
+                .await
+                .context("Failed to connect to server to probe manifest paths")?;
+            debug!("{} answered {}", path, response.status());
+            if response.status() == StatusCode::OK {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Where the manifest is fetched from: the configured manifest URL, else
+    /// the dated path of each shard, or of the default one.
+    fn manifest_urls(&self) -> Vec<String> {
+        if let Some(url) = &self.manifest_url {
+            return vec![if url.starts_with('/') {
+                format!("{}{}", self.panels_domain, url)
+            } else {
+                url.clone()
+            }];
+        }
+        if self.manifest_shards.is_empty() {
+            return vec![shard_url(&self.panels_domain, DEFAULT_MANIFEST_SHARD)];
+        }
+        self.manifest_shards
+            .iter()
+            .map(|shard| shard_url(&self.panels_domain, shard))
+            .collect()
+    }
+
+    async fn fetch_shards(&self) -> Result<Manifest> {
+        let manifest_client = self.manifest_client()?;
+        let retry_policy = self.manifest_retry_policy();
+        let urls = self.manifest_urls();
+        if self.respect_robots_txt {
+            self.robots_txt(&manifest_client)
+                .await?
+                .check(urls.iter().map(String::as_str))?;
+        }
+        if self.manifest_shards.is_empty() {
+            return fetch_manifest_shard(
+                &urls[0],
+                &manifest_client,
+                &retry_policy,
+                &self.field_map,
+                self.stream_manifest
+                    .then_some(|key: &str, data: &ManifestData| {
+                        self.is_streamed_entry_kept(key, data)
+                    }),
+            )
+            .await;
+        }
+        let fetches: Vec<_> = urls
+            .into_iter()
+            .map(|url| {
+                let client = manifest_client.clone();
+                let retry_policy = retry_policy.clone();
+                let field_map = self.field_map.clone();
+                let app = self.stream_manifest.then(|| self.clone());
+                tokio::spawn(async move {
+                    fetch_manifest_shard(
+                        &url,
+                        &client,
+                        &retry_policy,
+                        &field_map,
+                        app.as_ref().map(|app| {
+                            |key: &str, data: &ManifestData| app.is_streamed_entry_kept(key, data)
+                        }),
+                    )
+                    .await
+                })
+            })
+            .collect();
+        let mut merged: Option<Manifest> = None;
+        for (fetch, shard) in fetches.into_iter().zip(&self.manifest_shards) {
+            let manifest = fetch
+                .await
+                .context("Manifest fetch panicked")?
+                .with_context(|| format!("Failed to fetch manifest shard {}", shard))?;
+            merged = Some(match merged {
+                Some(merged) => merged.merge(manifest, self.shard_conflict)?,
+                None => manifest,
+            });
+        }
+        Ok(merged.expect("at least one shard was fetched"))
+    }
+
+    /// Plan the whole run up front: the manifest's download queue for the
+    /// configured strategy, limited to the entries that pass the filters and
+    /// optionally sorted by [`ManifestData::preferred_quality_tier`].
+    pub fn plan_downloads(&self, manifest: &Manifest) -> VecDeque<DownloadTask> {
+        let mut queue = manifest
+            .filter_by(|key, data| self.is_selected(key, data))
+            .to_download_queue(self.download_strategy);
+        if let Some(order) = self.quality_order {
+            // Stable, so entries of one tier keep their key order.
+            let tier = |task: &DownloadTask| manifest.data[&task.key].preferred_quality_tier();
+            queue.make_contiguous().sort_by(|a, b| match order {
+                // Tiers are declared best first, so descending quality is
+                // ascending tier.
+                SortOrder::Desc => tier(a).cmp(&tier(b)),
+                SortOrder::Asc => tier(b).cmp(&tier(a)),
+            });
+        }
+        let today = Local::now().date_naive();
+        for task in &mut queue {
+            self.lay_out(task, &today);
+        }
+        queue
+    }
+
+    /// Give `task` its path under the output directory: the naming strategy,
+    /// then the output template and hash directories if configured.
+    fn lay_out(&self, task: &mut DownloadTask, today: &NaiveDate) {
+        self.naming_strategy.apply(task);
+        if let Some(template) = &self.output_template {
+            let extension = Path::new(&task.filename)
+                .extension()
+                .map(|extension| extension.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let path = template.render(&task.key, today, &extension);
+            task.filename = path.to_string_lossy().into_owned();
+        }
+        if self.hash_directories {
+            task.filename = format!("{}/{}", hash_directory(&task.key), task.filename);
+        }
+    }
+
+    /// Whether the entry under `key` passes the key, category and animation
+    /// filters.
+    fn is_selected(&self, key: &str, data: &ManifestData) -> bool {
+        if let Some(prefix) = &self.key_prefix {
+            if !key.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        #[cfg(feature = "regex-filter")]
+        if let Some(regex) = &self.key_regex {
+            if !regex.is_match(key) {
+                return false;
+            }
+        }
+        if let Some(category) = &self.category {
+            if data.category() != Some(category.as_str()) {
+                return false;
+            }
+        }
+        if let Some(animated) = self.animated {
+            if data.is_animated() != animated {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether an entry of a streamed manifest is kept rather than dropped as
+    /// it arrives: it passes the filters and has a URL to download.
+    fn is_streamed_entry_kept(&self, key: &str, data: &ManifestData) -> bool {
+        data.has_any_url() && self.is_selected(key, data)
+    }
+
+    /// Download the selected wallpapers into memory instead of onto disk,
+    /// keyed by manifest key. The files are planned as for
+    /// [`run`](Self::run), keeping the first of each key, and fetched one at
+    /// a time with the same retries, host limits and [`ErrorStrategy`]. Fails
+    /// with [`PanelsError::MemoryLimitExceeded`] as soon as more than `limit`
+    /// bytes would be held in total.
+    pub async fn download_to_memory(&self, limit: usize) -> Result<HashMap<String, Bytes>> {
+        let manifest = self.fetch_manifest().await?;
+        let client = self.client(self.load_cookie_store()?)?;
+        let downloader = self.downloader(client, self.run_directory());
+        let mut queue = self.plan_downloads(&manifest);
+        let mut keys = HashSet::new();
+        queue.retain(|task| keys.insert(task.key.clone()));
+        let mut wallpapers = HashMap::new();
+        let mut total_bytes = 0;
+        let mut failed = 0;
+        for task in queue {
+            let over_limit = |bytes: usize| total_bytes + bytes > limit;
+            let downloaded = async {
+                let request = downloader.client.get(&task.url);
+                let (response, _permit) = task.send(&downloader, request).await?;
+                let mut response = response
+                    .error_for_status()
+                    .context("The server refused the wallpaper request")?;
+                if response
+                    .content_length()
+                    .is_some_and(|length| over_limit(length as usize))
+                {
+                    bail!(PanelsError::MemoryLimitExceeded(limit));
+                }
+                let mut buffer = BytesMut::new();
+                while let Some(chunk) = response
+                    .chunk()
+                    .await
+                    .context("Failed to recieve data from the server")?
+                {
+                    if over_limit(buffer.len() + chunk.len()) {
+                        bail!(PanelsError::MemoryLimitExceeded(limit));
+                    }
+                    buffer.extend_from_slice(&chunk);
+                }
+                Ok(buffer.freeze())
+            }
+            .await
+            .with_context(|| format!("Failed to download wallpaper {}", task.key));
+            match downloaded {
+                Ok(bytes) => {
+                    total_bytes += bytes.len();
+                    wallpapers.insert(task.key, bytes);
+                }
+                Err(e) if matches!(e.downcast_ref(), Some(PanelsError::MemoryLimitExceeded(_))) => {
+                    return Err(e)
+                }
+                Err(e) => match self.error_strategy {
+                    ErrorStrategy::FailFast => return Err(e),
+                    ErrorStrategy::BestEffort => warn!("{:#}", e),
+                    ErrorStrategy::FailSlow => {
+                        error!("{:#}", e);
+                        failed += 1;
+                    }
+                },
+            }
+        }
+        if failed > 0 && wallpapers.is_empty() {
+            bail!("All {} downloads failed", failed);
+        }
+        Ok(wallpapers)
+    }
+
+    /// Sum the `Content-Length` the server reports for every selected
+    /// wallpaper, using one HEAD request each. Wallpapers whose size is not
+    /// reported are left out of the total.
+    pub async fn estimate_total_size(&self) -> Result<u64> {
+        let manifest = self
+            .fetch_manifest()
+            .await?
+            .filter_by(|key, data| self.is_selected(key, data));
+        let client = self.client(self.load_cookie_store()?)?;
+        let sizes = manifest
+            .prefetch_sizes_with(&client, &self.retry_policy(), self.workers)
+            .await;
+        let mut total_bytes = 0;
+        for (key, size) in sizes {
+            match size {
+                Some(size) => total_bytes += size,
+                None => warn!("The server did not report a size for wallpaper {}", key),
+            }
+        }
+        Ok(total_bytes)
+    }
+
+    /// Find the files in the output directory and its subdirectories that
+    /// `manifest` would not produce, i.e. copies of wallpapers the server no
+    /// longer lists, and delete them if `confirm` is set. Returns the orphaned
+    /// paths either way. Files are matched without their extension, which a
+    /// download may have corrected. Hidden files and directories, and
+    /// symlinks, are left alone. An output directory that does not exist yet
+    /// has no orphans.
+    pub async fn delete_missing(&self, manifest: &Manifest, confirm: bool) -> Result<Vec<PathBuf>> {
+        let expected = self.expected_files(manifest);
+        let root = self.run_directory();
+        if !root.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut orphans = Vec::new();
+        let mut directories = vec![root.clone()];
+        while let Some(directory) = directories.pop() {
+            let mut entries = tokio::fs::read_dir(&directory)
+                .await
+                .with_context(|| format!("Failed to read {}", directory.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let path = entry.path();
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    directories.push(path);
+                } else if file_type.is_file() {
+                    let relative = path.strip_prefix(&root).unwrap_or(&path);
+                    if !expected.contains(&relative.with_extension("")) {
+                        orphans.push(path);
+                    }
+                }
+            }
+        }
+        orphans.sort();
+        if confirm {
+            for orphan in &orphans {
+                tokio::fs::remove_file(orphan)
+                    .await
+                    .with_context(|| format!("Failed to delete {}", orphan.display()))?;
+                info!("Deleted {}", orphan.display());
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// The path, relative to the run directory and without its extension, of
+    /// every file a download of `manifest` would produce, without the filters
+    /// applied. The extension is left out since a download may correct it.
+    fn expected_files(&self, manifest: &Manifest) -> HashSet<PathBuf> {
+        let today = Local::now().date_naive();
+        let mut expected = HashSet::new();
+        for mut task in manifest.to_download_queue(self.download_strategy) {
+            self.lay_out(&mut task, &today);
+            expected.insert(Path::new(&task.filename).with_extension(""));
+        }
+        expected
+    }
+
+    /// Tally the files in the output directory and its subdirectories without
+    /// downloading anything, counting which of them `manifest` would produce.
+    /// Hidden files and directories are skipped.
+    pub async fn directory_stats(&self, manifest: &Manifest) -> Result<DirectoryStats> {
+        let expected = self.expected_files(manifest);
+        let root = self.run_directory();
+        let mut stats = DirectoryStats::default();
+        let mut directories = vec![root.clone()];
+        while let Some(directory) = directories.pop() {
+            let mut entries = tokio::fs::read_dir(&directory)
+                .await
+                .with_context(|| format!("Failed to read {}", directory.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let path = entry.path();
+                let metadata = tokio::fs::metadata(&path)
+                    .await
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                if metadata.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+                let relative = path.strip_prefix(&root).unwrap_or(&path);
+                let in_manifest = expected.contains(&relative.with_extension(""));
+                let modified = metadata.modified().ok().map(DateTime::<Local>::from);
+                stats.record_file(path, metadata.len(), modified, in_manifest);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Find the `.tmp` files left in the output directory and its
+    /// subdirectories by downloads that never finished, with their sizes, and
+    /// delete them if `confirm` is set. Returns the files found either way.
+    pub async fn clean_partial_files(&self, confirm: bool) -> Result<Vec<(PathBuf, u64)>> {
+        let mut partial_files = Vec::new();
+        let mut directories = vec![self.download_directory.clone()];
+        while let Some(directory) = directories.pop() {
+            let mut entries = tokio::fs::read_dir(&directory)
+                .await
+                .with_context(|| format!("Failed to read {}", directory.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                let path = entry.path();
+                if file_type.is_dir() {
+                    directories.push(path);
+                } else if file_type.is_file() && path.extension() == Some("tmp".as_ref()) {
+                    partial_files.push((path, entry.metadata().await?.len()));
+                }
+            }
+        }
+        partial_files.sort();
+        if confirm {
+            for (path, _) in &partial_files {
+                tokio::fs::remove_file(path)
+                    .await
+                    .with_context(|| format!("Failed to delete {}", path.display()))?;
+                info!("Deleted {}", path.display());
+            }
+        }
+        Ok(partial_files)
+    }
+
+    /// Check, in order, that the server's name resolves, that it accepts a
+    /// TCP connection, that the manifest can be fetched and that the output
+    /// directory is writable. Every check runs even if an earlier one failed.
+    pub async fn self_test(&self) -> Vec<SelfTestStep> {
+        let mut steps = Vec::new();
+        let addresses = self.resolve().await;
+        let connected = match &addresses {
+            Ok(addresses) => self_test::connect(addresses, self_test::CONNECT_TIMEOUT).await,
+            Err(_) => Err(anyhow!("Skipped because the domain did not resolve")),
+        };
+        steps.push(SelfTestStep {
+            name: "DNS resolution",
+            result: addresses.map(|addresses| {
+                let addresses: Vec<_> = addresses.iter().map(|a| a.to_string()).collect();
+                format!("resolved to {}", addresses.join(", "))
+            }),
+            hint: "check --domain and the DNS settings of this machine or container",
+        });
+        steps.push(SelfTestStep {
+            name: "TCP connection",
+            result: connected.map(|address| format!("connected to {}", address)),
+            hint: "check that the server is running and that no firewall blocks the port",
+        });
+        steps.push(SelfTestStep {
+            name: "Manifest",
+            result: self.fetch_manifest().await.map(|manifest| {
+                format!("{} wallpapers listed", manifest.wallpapers().len())
+            }),
+            hint: "check that --domain points at a panels server and that any --manifest-shards exist",
+        });
+        let health = match self
+            .load_cookie_store()
+            .and_then(|store| self.client(store))
+        {
+            Ok(client) => self_test::health_check(&self.panels_domain, &client).await,
+            Err(e) => Err(e),
+        };
+        steps.push(SelfTestStep {
+            name: "Health check",
+            result: health.and_then(|health| {
+                if health.is_healthy() {
+                    return Ok(format!(
+                        "the manifest answered in {} ms and a sample wallpaper is reachable",
+                        health.latency_ms
+                    ));
+                }
+                let failed = [
+                    (!health.manifest_reachable).then_some("the manifest is not reachable"),
+                    (health.manifest_reachable && !health.manifest_parse_ok)
+                        .then_some("the manifest does not parse"),
+                    (health.manifest_parse_ok && !health.sample_wallpaper_reachable)
+                        .then_some("no sample wallpaper is reachable"),
+                ];
+                let failed: Vec<_> = failed.into_iter().flatten().collect();
+                Err(anyhow!("{}", failed.join(", ")))
+            }),
+            hint: "check that the server is healthy and serves the wallpapers its manifest lists",
+        });
+        let directory = self.run_directory();
+        steps.push(SelfTestStep {
+            name: "Output directory",
+            result: prepare_download_directory(&directory)
+                .map(|()| format!("{} is writable", directory.display())),
+            hint: "check that --output-dir is a directory this user can write to",
+        });
+        steps
+    }
+
+    /// Fetch the manifest and download the wallpapers it lists.
+    pub async fn run(&self) -> Result<Summary> {
+        let manifest = self.fetch_manifest().await?;
+        self.run_with_manifest(manifest).await
+    }
+
+    /// Fetch the manifest and return the tasks [`run`](Self::run) would
+    /// download, after every filter, the ignore file and the checkpoint, but
+    /// without downloading anything. The URL list is still written if one is
+    /// configured.
+    pub async fn run_dry(&self) -> Result<Vec<DownloadTask>> {
+        let manifest = self.fetch_manifest().await?;
+        let checkpoint = self.load_checkpoint()?;
+        Ok(self
+            .pending_downloads(&manifest, checkpoint.as_ref())?
+            .into())
+    }
+
+    fn load_checkpoint(&self) -> Result<Option<Checkpoint>> {
+        self.checkpoint.as_deref().map(Checkpoint::load).transpose()
+    }
+
+    /// The planned downloads of `manifest` minus the keys in the ignore file
+    /// and in `checkpoint`, and minus repeated URLs if deduplicating by URL,
+    /// written to the URL list if one is configured.
+    fn pending_downloads(
+        &self,
+        manifest: &Manifest,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<VecDeque<DownloadTask>> {
+        let mut queue = self.plan_downloads(manifest);
+        let ignored_keys = read_ignored_keys(&self.download_directory)?;
+        if !ignored_keys.is_empty() {
+            queue.retain(|task| !ignored_keys.contains(&task.key));
+            debug!(
+                "Ignoring {} keys listed in {}",
+                ignored_keys.len(),
+                IGNORE_FILE_NAME
+            );
+        }
+        if let Some(checkpoint) = checkpoint {
+            queue.retain(|task| !checkpoint.contains(&task.key));
+        }
+        // Only after the filters, so a URL is not dropped for a key they skip.
+        if self.deduplicate_by_url {
+            let mut planned = HashSet::new();
+            let before = queue.len();
+            queue.retain(|task| planned.insert(task.url.clone()));
+            if queue.len() < before {
+                debug!("Skipping {} duplicate URLs", before - queue.len());
+            }
+        }
+        if let Some(path) = &self.url_list {
+            write_url_list(&queue, path)?;
+        }
+        Ok(queue)
+    }
+
+    /// Wait for manifest files to appear in `directory` and download the
+    /// wallpapers of each with [`run_with_manifest`](Self::run_with_manifest),
+    /// handing its summary to `on_summary`. A processed manifest is moved to
+    /// a `processed` subdirectory so it is not picked up again; one that
+    /// fails to load or download is left in place. Runs until the watch fails.
+    pub async fn watch_manifest_directory(
+        &self,
+        directory: &Path,
+        mut on_summary: impl FnMut(&Path, Summary),
+    ) -> Result<()> {
+        let processed = directory.join("processed");
+        create_dir_all(&processed).context("Failed to make the processed manifests directory")?;
+        let (_watcher, mut manifests) = watch::watch_manifests(directory)?;
+        info!("Watching {} for manifests", directory.display());
+        while let Some(path) = manifests.recv().await {
+            // Creating and then closing a file reports it twice.
+            if !path.is_file() {
+                continue;
+            }
+            let summary = match Manifest::load(&path) {
+                Ok(manifest) => self.run_with_manifest(manifest).await,
+                Err(e) => Err(e),
+            };
+            match summary {
+                Ok(summary) => {
+                    on_summary(&path, summary);
+                    let file_name = path.file_name().expect("watched paths name a file");
+                    tokio::fs::rename(&path, processed.join(file_name))
+                        .await
+                        .with_context(|| format!("Failed to move {}", path.display()))?;
+                }
+                Err(e) => warn!("Failed to process {}: {:#}", path.display(), e),
+            }
+        }
+        bail!("Stopped receiving events for {}", directory.display())
+    }
+
+    /// Download the wallpapers of a manifest the caller already has, e.g. one
+    /// fetched earlier, filtered with [`Manifest::filter_by`] or built by hand.
+    /// Entries without any URL are dropped first.
+    pub async fn run_with_manifest(&self, mut manifest: Manifest) -> Result<Summary> {
+        let removed = manifest.shrink_to_fit();
+        debug!("Removed {} manifest entries without URLs", removed);
+        self.run_selected(&manifest, |queue| queue).await
+    }
+
+    /// Fetch the manifest and download only the first `n` of the wallpapers
+    /// [`run`](Self::run) would, returning what is left as a continuation
+    /// for [`continue_from`](Self::continue_from).
+    pub async fn run_n_then_pause(&self, n: usize) -> Result<(Summary, DownloadContinuation)> {
+        let manifest = self.fetch_manifest().await?;
+        let mut remaining = VecDeque::new();
+        let summary = self
+            .run_selected(&manifest, |mut queue| {
+                remaining = queue.split_off(n.min(queue.len()));
+                queue
+            })
+            .await?;
+        Ok((summary, DownloadContinuation::new(&manifest, &remaining)))
+    }
+
+    /// Download what an earlier [`run_n_then_pause`](Self::run_n_then_pause)
+    /// left, without fetching the manifest again. Files are planned afresh,
+    /// so keys in the ignore file or the checkpoint since then are skipped.
+    pub async fn continue_from(&self, continuation: DownloadContinuation) -> Result<Summary> {
+        self.run_selected(&continuation.manifest, |mut queue| {
+            queue.retain(|task| continuation.contains(task));
+            queue
+        })
+        .await
+    }
+
+    /// Download the pending wallpapers of `manifest` that `select` keeps.
+    async fn run_selected(
+        &self,
+        manifest: &Manifest,
+        select: impl FnOnce(VecDeque<DownloadTask>) -> VecDeque<DownloadTask>,
+    ) -> Result<Summary> {
+        let started = Instant::now();
+        let _pid_file = self.pid_file.as_deref().map(PidFile::acquire).transpose()?;
+        let download_directory = self.run_directory();
+        prepare_download_directory(&download_directory)?;
+        if let Some(symlinks_dir) = &self.symlinks_directory {
+            create_dir_all(symlinks_dir).context("Failed to make symlinks directory")?;
+        }
+        let metrics = Arc::clone(self.metrics().await?);
+        debug!("{}", manifest);
+        let mut checkpoint = self.load_checkpoint()?;
+        let queue = select(self.pending_downloads(manifest, checkpoint.as_ref())?);
+        let cookie_store = self.load_cookie_store()?;
+        let client = self.client(cookie_store.clone())?;
+        if self.respect_robots_txt {
+            self.robots_txt(&client)
+                .await?
+                .check(queue.iter().map(|task| task.url.as_str()))?;
+        }
+        info!("Downloading {} wallpapers", queue.len());
+        self.progress
+            .emit(ProgressEvent::ManifestFetched { total: queue.len() });
+        let status = self
+            .status_file
+            .clone()
+            .map(|path| Arc::new(StatusFile::new(path, queue.len())));
+        let task_sources: Vec<_> = match self.max_workers {
+            Some(max_workers) => {
+                let scaling = Arc::new(WorkerScaling::new(
+                    self.workers,
+                    max_workers,
+                    self.slow_download_threshold,
+                ));
+                let queue = Arc::new(Mutex::new(queue));
+                (0..scaling.max_workers())
+                    .map(|index| WorkerTasks::Shared {
+                        index,
+                        queue: Arc::clone(&queue),
+                        scaling: Arc::clone(&scaling),
+                    })
+                    .collect()
+            }
+            None => {
+                let mut task_lists = Vec::with_capacity(self.workers);
+                for _ in 0..self.workers {
+                    task_lists.push(Vec::new());
+                }
+                for (i, task) in queue.into_iter().enumerate() {
+                    task_lists[i % self.workers].push(task);
+                }
+                task_lists
+                    .into_iter()
+                    .map(|task_list| WorkerTasks::Dealt(task_list.into_iter()))
+                    .collect()
+            }
+        };
+        let summary = Arc::new(Mutex::new(Summary::default()));
+        let completed_keys = Arc::new(Mutex::new(BTreeSet::new()));
+        let downloaded_keys = Arc::new(Mutex::new(HashSet::new()));
+        let circuit_breaker = self.circuit_breaker_threshold.map(|threshold| {
+            Arc::new(Mutex::new(CircuitBreaker::new(
+                threshold,
+                self.circuit_breaker_reset_timeout,
+            )))
+        });
+        let downloader = self.downloader(client, download_directory.clone());
+        let mut workers = Vec::with_capacity(task_sources.len());
+        for mut tasks in task_sources {
+            let downloader = downloader.clone();
+            let symlinks_dir = self.symlinks_directory.clone();
+            let file_mode = self.file_mode;
+            let metrics = Arc::clone(&metrics);
+            let progress = self.progress.clone();
+            let summary = Arc::clone(&summary);
+            let completed_keys = Arc::clone(&completed_keys);
+            let status = status.clone();
+            let downloaded_keys = Arc::clone(&downloaded_keys);
+            let circuit_breaker = circuit_breaker.clone();
+            let error_strategy = self.error_strategy;
+            let mut rate_limiter = self.rate_limit.map(TokenBucket::new);
+            #[cfg(feature = "exif")]
+            let xmp_tags = self.xmp_tags.clone();
+            workers.push(async move {
+                // Counts this worker as active while it takes tasks.
+                let mut active_worker = None;
+                while let Some(task) = tokio::select! {
+                    task = tasks.next(&mut active_worker, || {
+                        let status_worker = status.as_ref().map(|status| status.worker_started());
+                        (metrics.worker_started(), status_worker)
+                    }) => task,
+                    () = downloader.cancel.cancelled() => None,
+                } {
+                    let key = task.key.clone();
+                    if let Some(breaker) = &circuit_breaker {
+                        if !breaker.lock().unwrap().allow_request() {
+                            debug!("Circuit breaker is open, skipping wallpaper {}", key);
+                            summary.lock().unwrap().record_skip();
+                            if let Some(status) = &status {
+                                status.record_not_downloaded();
+                            }
+                            continue;
+                        }
+                    }
+                    debug!("Downloading wallpaper {}", key);
+                    progress.emit(ProgressEvent::DownloadStarted { key: key.clone() });
+                    let download_started = Instant::now();
+                    // Names the wallpaper in every error of its download.
+                    let context = || format!("Failed to download wallpaper {}", key);
+                    let downloaded = task
+                        .download(
+                            &downloader,
+                            rate_limiter.as_mut(),
+                            |bytes_received, total_bytes| {
+                                progress.emit(ProgressEvent::DownloadProgress {
+                                    key: key.clone(),
+                                    bytes_received,
+                                    total_bytes,
+                                })
+                            },
+                        )
+                        .await;
+                    tasks.record(download_started.elapsed());
+                    // A download only counts once its post-processing is done too.
+                    let downloaded = match downloaded {
+                        Ok(Some((path, bytes))) => {
+                            async {
+                                #[cfg(feature = "exif")]
+                                if !xmp_tags.is_empty() {
+                                    xmp::embed(&path, &task, &xmp_tags).await?;
+                                }
+                                #[cfg(unix)]
+                                if let Some(mode) = file_mode {
+                                    set_file_mode(&path, mode).await?;
+                                }
+                                if let Some(symlinks_dir) = &symlinks_dir {
+                                    let link = symlinks_dir.join(format!("{}.jpg", key));
+                                    replace_symlink(&path, &link).await?;
+                                }
+                                Ok(Some((path, bytes)))
+                            }
+                            .await
+                        }
+                        downloaded => downloaded,
+                    }
+                    .with_context(context);
+                    let (path, bytes) = match downloaded {
+                        Ok(Some(downloaded)) => downloaded,
+                        Ok(None) => {
+                            debug!("Wallpaper {} has not been modified, skipping", key);
+                            if let Some(breaker) = &circuit_breaker {
+                                breaker.lock().unwrap().record_success();
+                            }
+                            summary.lock().unwrap().record_skip();
+                            if let Some(status) = &status {
+                                status.record_not_downloaded();
+                            }
+                            completed_keys.lock().unwrap().insert(key);
+                            continue;
+                        }
+                        // Neither downloaded nor failed, the run just ran out of time.
+                        Err(_) if downloader.cancel.is_cancelled() => break,
+                        Err(e) => {
+                            if let Some(breaker) = &circuit_breaker {
+                                breaker.lock().unwrap().record_failure();
+                            }
+                            metrics.download_failed();
+                            summary.lock().unwrap().record_failure(&key, &e);
+                            if let Some(status) = &status {
+                                status.record_not_downloaded();
+                            }
+                            progress.emit(ProgressEvent::DownloadFailed {
+                                key,
+                                error: format!("{:#}", e),
+                            });
+                            match error_strategy {
+                                ErrorStrategy::FailFast => return Err(e),
+                                ErrorStrategy::BestEffort => continue,
+                                ErrorStrategy::FailSlow => {
+                                    error!("{:#}", e);
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    if let Some(breaker) = &circuit_breaker {
+                        breaker.lock().unwrap().record_success();
+                    }
+                    metrics.download_succeeded(bytes);
+                    summary.lock().unwrap().record_download(bytes);
+                    if let Some(status) = &status {
+                        status.record_download(bytes);
+                    }
+                    info!("Saved wallpaper {} to {}", key, path.display());
+                    completed_keys.lock().unwrap().insert(key.clone());
+                    downloaded_keys.lock().unwrap().insert(key.clone());
+                    progress.emit(ProgressEvent::DownloadComplete { key, path, bytes });
+                }
+                Ok(())
+            });
+        }
+        let worker_count = workers.len();
+        let status_updates = status
+            .as_ref()
+            .map(|status| status.spawn_updates(self.status_interval));
+        let fail_fast = self.error_strategy == ErrorStrategy::FailFast;
+        let mut failed_workers = 0;
+        // Returned by the run, so only the errors after it are logged here.
+        let mut first_error = None;
+        // Whether the run should stop here.
+        let mut record_worker = |result: Result<()>| match result {
+            Ok(()) => false,
+            Err(e) => {
+                failed_workers += 1;
+                if first_error.is_none() {
+                    first_error = Some(e);
+                } else {
+                    error!("{:#}", e);
+                }
+                fail_fast
+            }
+        };
+        let join_workers = async {
+            match self.scheduler {
+                Scheduler::JoinSet => {
+                    let mut futures = JoinSet::new();
+                    for worker in workers {
+                        futures.spawn(worker);
+                    }
+                    while let Some(result) = futures.join_next().await {
+                        let stop = match result {
+                            Err(e) if e.is_cancelled() => false,
+                            result => record_worker(result.unwrap_or_else(|e| {
+                                Err(anyhow::Error::new(e).context("Download worker panicked"))
+                            })),
+                        };
+                        if stop {
+                            futures.abort_all();
+                        }
+                    }
+                }
+                Scheduler::FuturesUnordered => {
+                    let mut futures: FuturesUnordered<_> = workers.into_iter().collect();
+                    while let Some(result) = futures.next().await {
+                        if record_worker(result) {
+                            break;
+                        }
+                    }
+                }
+            }
+        };
+        let timed_out = {
+            let mut join_workers = pin!(join_workers);
+            match self.total_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, &mut join_workers).await {
+                    Ok(()) => false,
+                    Err(_) => {
+                        warn!(
+                            "Ran out of time after {:?}, cancelling the downloads",
+                            timeout
+                        );
+                        downloader.cancel.cancel();
+                        join_workers.await;
+                        true
+                    }
+                },
+                None => {
+                    join_workers.await;
+                    false
+                }
+            }
+        };
+        let all_downloads_failed = {
+            let summary = summary.lock().unwrap();
+            summary.downloaded == 0 && summary.failed > 0
+        };
+        let run_failed = failed_workers > 0
+            || timed_out
+            || (self.error_strategy == ErrorStrategy::FailSlow && all_downloads_failed);
+        if let (Some(path), Some(cookie_store)) = (&self.cookie_file, &cookie_store) {
+            if self.save_cookies {
+                cookies::save_cookie_store(cookie_store, path)?;
+            }
+        }
+        if let (Some(status), Some(updates)) = (&status, status_updates) {
+            updates.abort();
+            status.finish(!run_failed).await?;
+        }
+        let mut summary = summary.lock().unwrap().clone();
+        if let Some(checkpoint) = &mut checkpoint {
+            // A key with several variants is only done once none of them failed.
+            let mut completed_keys = completed_keys.lock().unwrap().clone();
+            for (key, _) in &summary.errors {
+                completed_keys.remove(key);
+            }
+            checkpoint.append(&completed_keys)?;
+        }
+        if let Some(path) = &self.manifest_subset {
+            let mut downloaded_keys = downloaded_keys.lock().unwrap().clone();
+            for (key, _) in &summary.errors {
+                downloaded_keys.remove(key);
+            }
+            manifest
+                .filter_by(|key, _| downloaded_keys.contains(key))
+                .save(path)?;
+        }
+        summary.elapsed = started.elapsed();
+        self.progress.emit(ProgressEvent::AllComplete {
+            summary: summary.clone(),
+        });
+        if let Some(timeout) = self.total_timeout.filter(|_| timed_out) {
+            return Err(PanelsError::TotalTimeoutExceeded { timeout, summary }.into());
+        }
+        if let Some(e) = first_error {
+            if fail_fast {
+                return Err(e);
+            }
+            return Err(e.context(format!(
+                "{} of {} download workers failed",
+                failed_workers, worker_count
+            )));
+        }
+        if run_failed {
+            bail!("All {} downloads failed", summary.failed);
+        }
+        if let Some(latest) = &self.latest_symlink {
+            replace_directory_symlink(&download_directory, latest).await?;
+        }
+        if self.open_when_done {
+            if let Err(e) = open::that_detached(&download_directory) {
+                warn!("Failed to open {}: {}", download_directory.display(), e);
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// `domain` with a scheme: `https://` unless `insecure_http` asks for plain
+/// `http://`. Explicit `http://` domains are deprecated without it.
+fn resolve_domain(domain: &str, insecure_http: bool) -> String {
+    if domain.starts_with("https://") {
+        return domain.to_string();
+    }
+    if domain.starts_with("http://") {
+        if !insecure_http {
+            warn!(
+                "Reaching {} over plain HTTP is deprecated, pass --insecure-http to keep doing so",
+                domain
+            );
+        }
+        return domain.to_string();
+    }
+    let scheme = if insecure_http { "http" } else { "https" };
+    format!("{}://{}", scheme, domain)
+}
+
+/// The first character in `path` that the platform cannot store in a file
+/// name. NUL is rejected everywhere, Windows also reserves a few punctuation
+/// characters.
+fn invalid_path_character(path: &Path) -> Option<char> {
+    let reserved: &[char] = if cfg!(windows) {
+        &['\0', '<', '>', '"', '|', '?', '*']
+    } else {
+        &['\0']
+    };
+    path.to_string_lossy()
+        .chars()
+        .find(|character| reserved.contains(character))
+}
+
+/// Make sure `path` is a directory we can write into before any work starts.
+fn prepare_download_directory(path: &Path) -> Result<()> {
+    if path.exists() && !path.is_dir() {
+        return Err(PanelsError::OutputPathIsNotDirectory(path.to_path_buf()).into());
+    }
+    create_dir_all(path).context(
+        "Failed to make download directory. Please make sure you have write permissions",
+    )?;
+    tempfile::tempfile_in(path)
+        .map_err(|e| PanelsError::OutputDirectoryNotWritable(path.to_path_buf(), e))?;
+    Ok(())
+}
+
+/// Fetch the manifest or manifest shard at `url`. With `stream_filter` it is
+/// parsed as it downloads, keeping only the entries the filter accepts.
+async fn fetch_manifest_shard(
+    url: &str,
+    client: &Client,
+    retry_policy: &RetryPolicy,
+    field_map: &FieldMap,
+    stream_filter: Option<impl Fn(&str, &ManifestData) -> bool>,
+) -> Result<Manifest> {
+    match stream_filter {
+        Some(keep) => {
+            let items = Manifest::stream_from(url, client, retry_policy);
+            Manifest::from_stream(items, keep).await
+        }
+        None => Manifest::fetch_from(url, client, retry_policy, field_map).await,
+    }
+}
+
+// Serializers used to print the configuration of an `AppBuilder`.
+
+fn serialize_optional_secs<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration.map(|d| d.as_secs_f64()).serialize(serializer)
+}
+
+#[cfg(feature = "regex-filter")]
+fn serialize_regex<S: Serializer>(
+    regex: &Option<regex::Regex>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    regex
+        .as_ref()
+        .map(regex::Regex::as_str)
+        .serialize(serializer)
+}
+
+fn serialize_sorted<S: Serializer>(codes: &HashSet<u16>, serializer: S) -> Result<S::Ok, S::Error> {
+    codes.iter().collect::<BTreeSet<_>>().serialize(serializer)
+}
+
+/// Header values often carry credentials, so only the names are shown.
+fn serialize_redacted<S: Serializer>(
+    headers: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    headers
+        .keys()
+        .map(|name| (name, "<redacted>"))
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+/// Write the URL of every planned download to `path`, one per line.
+fn write_url_list(queue: &VecDeque<DownloadTask>, path: &Path) -> Result<()> {
+    let file = File::create(path).context("Failed to create the URL list")?;
+    let mut writer = BufWriter::new(file);
+    for task in queue {
+        writeln!(writer, "{}", task.url).context("Failed to write the URL list")?;
+    }
+    writer.flush().context("Failed to write the URL list")
+}
+
+/// Replace the umask-derived permissions of a freshly written file.
+#[cfg(unix)]
+async fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .context("Failed to set wallpaper file permissions")
+}
+
+/// Point `link` at the absolute path of `target`, replacing a link left
+/// behind by a previous run but never a real file, such as a download when
+/// the symlinks directory is inside the output directory.
+async fn replace_symlink(target: &Path, link: &Path) -> Result<()> {
+    let target = tokio::fs::canonicalize(target)
+        .await
+        .context("Failed to resolve the downloaded wallpaper path")?;
+    if let Some(parent) = link.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("Failed to make the symlink's directory")?;
+    }
+    if let Ok(metadata) = tokio::fs::symlink_metadata(link).await {
+        if !metadata.file_type().is_symlink() {
+            bail!("{} exists and is not a symlink", link.display());
+        }
+        tokio::fs::remove_file(link)
+            .await
+            .context("Failed to remove the existing symlink")?;
+    }
+    #[cfg(unix)]
+    tokio::fs::symlink(&target, link)
+        .await
+        .context("Failed to create symlink")?;
+    #[cfg(windows)]
+    tokio::fs::symlink_file(&target, link)
+        .await
+        .context("Failed to create symlink")?;
+    Ok(())
+}
+
+/// Point `link` at the absolute path of the directory `target`, replacing a
+/// link left behind by a previous run but never a real file or directory.
+async fn replace_directory_symlink(target: &Path, link: &Path) -> Result<()> {
+    let target = tokio::fs::canonicalize(target)
+        .await
+        .context("Failed to resolve the output directory path")?;
+    if let Ok(metadata) = tokio::fs::symlink_metadata(link).await {
+        if !metadata.file_type().is_symlink() {
+            bail!("{} exists and is not a symlink", link.display());
+        }
+        #[cfg(unix)]
+        tokio::fs::remove_file(link)
+            .await
+            .context("Failed to remove the existing symlink")?;
+        #[cfg(windows)]
+        tokio::fs::remove_dir(link)
+            .await
+            .context("Failed to remove the existing symlink")?;
+    }
+    #[cfg(unix)]
+    tokio::fs::symlink(&target, link)
+        .await
+        .context("Failed to create symlink")?;
+    #[cfg(windows)]
+    tokio::fs::symlink_dir(&target, link)
+        .await
+        .context("Failed to create symlink")?;
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/// The manifest keys already downloaded by earlier runs, one per line in a
+/// file that grows as new keys are downloaded.
+#[derive(Debug)]
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    keys: HashSet<String>,
+}
+
+impl Checkpoint {
+    /// Read the checkpoint at `path`. A missing file is an empty checkpoint,
+    /// so the first run downloads everything.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let keys = match std::fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            keys,
+        })
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Add the keys that are not in the checkpoint yet to the end of its file.
+    pub(crate) fn append<'a>(&mut self, keys: impl IntoIterator<Item = &'a String>) -> Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        let mut writer = BufWriter::new(file);
+        for key in keys {
+            if self.keys.insert(key.clone()) {
+                writeln!(writer, "{}", key).context("Failed to write the checkpoint")?;
+            }
+        }
+        writer.flush().context("Failed to write the checkpoint")
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests flow normally.
+    Closed,
+    /// Too many consecutive failures; requests are skipped until the reset
+    /// timeout has passed.
+    Open,
+    /// The reset timeout passed and a single probe request is in flight.
+    HalfOpen,
+}
+
+/// Stops hammering the panels server once it looks down. Shared between the
+/// workers behind an `Arc<Mutex<_>>`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: CircuitState,
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, reset_timeout: Duration) -> Self {
+        Self {
+            state: CircuitState::Closed,
+            failure_threshold: failure_threshold.max(1),
+            reset_timeout,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Whether a request may be sent now. Once the reset timeout of an open
+    /// circuit has passed, exactly one caller is let through as the probe.
+    pub fn allow_request(&mut self) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen => false,
+            CircuitState::Open => {
+                let expired = self
+                    .opened_at
+                    .is_some_and(|opened_at| opened_at.elapsed() >= self.reset_timeout);
+                if expired {
+                    self.state = CircuitState::HalfOpen;
+                }
+                expired
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        if self.state == CircuitState::HalfOpen
+            || self.consecutive_failures >= self.failure_threshold
+        {
+            self.state = CircuitState::Open;
+            self.opened_at = Some(Instant::now());
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::manifest::Manifest;
+use crate::task::DownloadTask;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+/// The downloads an [`App::run_n_then_pause`](crate::App::run_n_then_pause)
+/// left for later, with the manifest entries they came from, so they can be
+/// resumed with [`App::continue_from`](crate::App::continue_from) without
+/// fetching the manifest again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadContinuation {
+    pub(crate) manifest: Manifest,
+    /// `(key, url)` of every remaining download.
+    remaining: HashSet<(String, String)>,
+}
+
+impl DownloadContinuation {
+    pub(crate) fn new(manifest: &Manifest, remaining: &VecDeque<DownloadTask>) -> Self {
+        let keys: HashSet<_> = remaining.iter().map(|task| task.key.as_str()).collect();
+        Self {
+            manifest: manifest.filter_by(|key, _| keys.contains(key)),
+            remaining: remaining
+                .iter()
+                .map(|task| (task.key.clone(), task.url.clone()))
+                .collect(),
+        }
+    }
+
+    /// How many downloads are left.
+    pub fn len(&self) -> usize {
+        self.remaining.len()
+    }
+
+    /// Whether nothing is left, so there is no need to continue.
+    pub fn is_empty(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    pub(crate) fn contains(&self, task: &DownloadTask) -> bool {
+        self.remaining
+            .contains(&(task.key.clone(), task.url.clone()))
+    }
+
+    /// Read a continuation written by [`DownloadContinuation::save`].
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).context("Failed to open continuation file")?;
+        serde_json::from_reader(BufReader::new(file))
+            .context("Unable to parse the continuation json")
+    }
+
+    /// Write the continuation to `path` as json.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut writer =
+            BufWriter::new(File::create(path).context("Failed to create continuation file")?);
+        serde_json::to_writer(&mut writer, self)
+            .context("Failed to write the continuation json")?;
+        writer
+            .flush()
+            .context("Failed to write the continuation json")
+    }
+}
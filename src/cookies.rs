@@ -0,0 +1,150 @@
+use anyhow::{anyhow, bail, Context, Result};
+use cookie_store::{CookieDomain, CookieExpiration, RawCookie};
+use reqwest::Url;
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Load the Netscape cookie jar at `path`, in the format `curl --cookie-jar`
+/// writes. A missing file gives an empty jar so the first run of a login
+/// flow can create it.
+pub fn load_cookie_store(path: &Path) -> Result<CookieStore> {
+    if !path.exists() {
+        return Ok(CookieStore::default());
+    }
+    let contents = std::fs::read_to_string(path).context("Failed to read cookie file")?;
+    let mut store = CookieStore::default();
+    insert_netscape_cookies(&mut store, &contents).context("Failed to parse cookie file")?;
+    Ok(store)
+}
+
+/// Write the jar back to `path` in the Netscape format, keeping session
+/// cookies so the next run can reuse them. Expired cookies are left out.
+pub fn save_cookie_store(store: &CookieStoreMutex, path: &Path) -> Result<()> {
+    let store = store
+        .lock()
+        .map_err(|_| anyhow!("Cookie store lock was poisoned"))?;
+    let mut writer = BufWriter::new(File::create(path).context("Failed to open cookie file")?);
+    write_netscape_cookies(&store, &mut writer).context("Failed to write cookie file")
+}
+
+/// Write the unexpired cookies of `store` as a Netscape jar, the format
+/// [`insert_netscape_cookies`] reads.
+fn write_netscape_cookies(store: &CookieStore, writer: &mut impl Write) -> io::Result<()> {
+    writeln!(writer, "# Netscape HTTP Cookie File")?;
+    for cookie in store.iter_unexpired() {
+        let (domain, include_subdomains) = match &cookie.domain {
+            CookieDomain::HostOnly(host) => (host.clone(), "FALSE"),
+            CookieDomain::Suffix(suffix) => (format!(".{}", suffix), "TRUE"),
+            CookieDomain::NotPresent | CookieDomain::Empty => continue,
+        };
+        let expiry = match &cookie.expires {
+            CookieExpiration::AtUtc(at) => at.unix_timestamp().max(1),
+            CookieExpiration::SessionEnd => 0,
+        };
+        let http_only = if cookie.http_only() == Some(true) {
+            "#HttpOnly_"
+        } else {
+            ""
+        };
+        let secure = if cookie.secure() == Some(true) {
+            "TRUE"
+        } else {
+            "FALSE"
+        };
+        writeln!(
+            writer,
+            "{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            http_only,
+            domain,
+            include_subdomains,
+            cookie.path.as_ref(),
+            secure,
+            expiry,
+            cookie.name(),
+            cookie.value()
+        )?;
+    }
+    writer.flush()
+}
+
+/// Reads cookie jars written by other tools.
+pub struct CookieParser;
+
+impl CookieParser {
+    /// Load the Netscape `cookies.txt` jar curl and wget write with
+    /// `--cookie-jar` and `--save-cookies`. Cookies that have already expired
+    /// are skipped.
+    pub fn from_netscape_file(path: &Path) -> Result<CookieStore> {
+        let contents =
+            std::fs::read_to_string(path).context("Failed to read the cookies.txt file")?;
+        let mut store = CookieStore::default();
+        insert_netscape_cookies(&mut store, &contents)?;
+        Ok(store)
+    }
+}
+
+/// Add the cookies of a Netscape jar to `store`. Each line holds the tab
+/// separated domain, subdomain flag, path, secure flag, expiry as a unix
+/// timestamp (0 for a session cookie), name and value.
+pub(crate) fn insert_netscape_cookies(store: &mut CookieStore, contents: &str) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for (number, line) in contents.lines().enumerate() {
+        // curl marks HttpOnly cookies with a prefix that looks like a comment.
+        let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split('\t').collect();
+        let [domain, include_subdomains, path, secure, expiry, name, rest @ ..] = fields.as_slice()
+        else {
+            bail!(
+                "Line {} of the cookies.txt file has too few fields",
+                number + 1
+            );
+        };
+        let value = rest.first().copied().unwrap_or_default();
+        let expiry: u64 = expiry
+            .parse()
+            .with_context(|| format!("Line {} has an invalid expiry", number + 1))?;
+        if expiry != 0 && expiry <= now {
+            continue;
+        }
+        let host = domain.trim_start_matches('.');
+        let secure = secure.eq_ignore_ascii_case("TRUE");
+        let scheme = if secure { "https" } else { "http" };
+        let url = Url::parse(&format!("{}://{}{}", scheme, host, path))
+            .with_context(|| format!("Line {} has an invalid domain or path", number + 1))?;
+        // Leaving the domain out makes a host-only cookie.
+        let mut set_cookie = format!("{}={}; Path={}", name, value, path);
+        if include_subdomains.eq_ignore_ascii_case("TRUE") {
+            set_cookie.push_str(&format!("; Domain={}", host));
+        }
+        if expiry != 0 {
+            set_cookie.push_str(&format!("; Max-Age={}", expiry - now));
+        }
+        if secure {
+            set_cookie.push_str("; Secure");
+        }
+        if http_only {
+            set_cookie.push_str("; HttpOnly");
+        }
+        let cookie = RawCookie::parse(set_cookie)
+            .with_context(|| format!("Line {} holds an invalid cookie", number + 1))?;
+        store
+            .insert_raw(&cookie, &url)
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("Line {} holds a cookie that cannot be stored", number + 1))?;
+    }
+    Ok(())
+}
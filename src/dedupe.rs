@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The files written so far in a run, keyed by the SHA-256 of their content,
+/// so a wallpaper listed under several keys is only stored once.
+#[derive(Debug, Default)]
+pub(crate) struct ContentIndex {
+    files: Mutex<HashMap<[u8; 32], PathBuf>>,
+}
+
+impl ContentIndex {
+    /// Record that `path` holds content hashing to `digest`. If an earlier
+    /// file holds the same content, `path` is replaced by a hard link to it
+    /// and the earlier file is returned.
+    pub(crate) async fn link_duplicate(
+        &self,
+        digest: [u8; 32],
+        path: &Path,
+    ) -> Result<Option<PathBuf>> {
+        let original = match self.files.lock().unwrap().entry(digest) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                entry.insert(path.to_path_buf());
+                return Ok(None);
+            }
+        };
+        tokio::fs::remove_file(path)
+            .await
+            .context("Failed to remove duplicate wallpaper")?;
+        tokio::fs::hard_link(&original, path)
+            .await
+            .context("Failed to link duplicate wallpaper")?;
+        Ok(Some(original))
+    }
+}
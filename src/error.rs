@@ -0,0 +1,48 @@
+use crate::summary::Summary;
+use std::{io, path::PathBuf, time::Duration};
+use thiserror::Error;
+
+/// Failures callers may want to tell apart. They travel inside
+/// `anyhow::Error` and can be recovered with `downcast_ref`.
+#[derive(Debug, Error)]
+pub enum PanelsError {
+    #[error("The output path {} exists but is not a directory", .0.display())]
+    OutputPathIsNotDirectory(PathBuf),
+    #[error("The output directory {} is not writable", .0.display())]
+    OutputDirectoryNotWritable(PathBuf, #[source] io::Error),
+    #[error("Downloading the wallpapers would hold more than {0} bytes in memory")]
+    MemoryLimitExceeded(usize),
+    #[error("Another instance{} is already running on this output directory", pid_suffix(.0))]
+    AnotherInstanceRunning(Option<u32>),
+    #[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+    InvalidConfiguration(Vec<String>),
+    #[error("The server {0} is unreachable")]
+    ServerUnreachable(String),
+    #[error("robots.txt does not allow fetching {0}")]
+    DisallowedByRobots(String),
+    /// The run was cancelled at its total timeout. The summary covers the
+    /// downloads that finished before that.
+    #[error("Did not finish within {timeout:?}: {summary}")]
+    TotalTimeoutExceeded { timeout: Duration, summary: Summary },
+    #[error("Gave up on {0} after using up its retries across all downloads")]
+    HostRetriesExhausted(String),
+    /// The body was cut off, e.g. because the connection dropped, or did not
+    /// match the `Content-Length` the server announced. The partial file has
+    /// been removed, so the download can simply be tried again.
+    #[error("Only received {received}{} bytes of {url}", expected_suffix(.expected))]
+    IncompleteDownload {
+        url: String,
+        expected: Option<u64>,
+        received: u64,
+    },
+}
+
+fn pid_suffix(pid: &Option<u32>) -> String {
+    pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default()
+}
+
+fn expected_suffix(expected: &Option<u64>) -> String {
+    expected
+        .map(|expected| format!(" of {}", expected))
+        .unwrap_or_default()
+}
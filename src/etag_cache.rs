@@ -0,0 +1,74 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// A directory of `<hash>.etag` files remembering the `ETag` the server sent
+/// for each wallpaper URL, so unchanged wallpapers can be skipped with a
+/// conditional GET on the next run.
+#[derive(Debug, Clone)]
+pub struct EtagCache {
+    directory: PathBuf,
+}
+
+/// What the etag cache remembers about a wallpaper URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EtagEntry {
+    pub etag: String,
+    /// Where the wallpaper was saved, relative to the download directory.
+    /// This differs from the planned file name when its extension was
+    /// corrected, and is `None` for entries of older versions.
+    pub file: Option<PathBuf>,
+}
+
+impl EtagCache {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// The stored entry for `url`, if any. An unreadable entry is treated as
+    /// missing, which only costs a full download.
+    pub async fn load(&self, url: &str) -> Option<EtagEntry> {
+        let entry = fs::read_to_string(self.entry_path(url)).await.ok()?;
+        let mut lines = entry.lines().map(str::trim);
+        let etag = lines.next().filter(|etag| !etag.is_empty())?;
+        Some(EtagEntry {
+            etag: etag.to_string(),
+            file: lines
+                .next()
+                .filter(|file| !file.is_empty())
+                .map(PathBuf::from),
+        })
+    }
+
+    /// Remember `etag` for `url` and the `file` it was saved as, creating the
+    /// cache directory if needed.
+    pub async fn store(&self, url: &str, etag: &str, file: &Path) -> Result<()> {
+        fs::create_dir_all(&self.directory)
+            .await
+            .context("Failed to make etag cache directory")?;
+        fs::write(
+            self.entry_path(url),
+            format!("{}\n{}\n", etag, file.to_string_lossy()),
+        )
+        .await
+        .context("Failed to write etag cache entry")
+    }
+
+    fn entry_path(&self, url: &str) -> PathBuf {
+        self.directory.join(format!("{:016x}.etag", fnv1a(url)))
+    }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is fixed, so entry names
+/// stay valid across builds of the tool.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Renames the fields of manifest entries served by forks of the panels API
+/// that do not use the standard names, e.g. `high_def` for `dhd`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FieldMap {
+    /// Incoming field name to the standard name it stands for.
+    renames: HashMap<String, String>,
+}
+
+impl FieldMap {
+    pub fn new(renames: HashMap<String, String>) -> Self {
+        Self { renames }
+    }
+
+    /// Parse a json object of renames, e.g. `{"high_def":"dhd"}`.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let renames = serde_json::from_str(json)
+            .context("The field map should be a json object of field names")?;
+        Ok(Self::new(renames))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.renames.is_empty()
+    }
+
+    /// Rename the fields of every entry of a manifest's `data`, replacing a
+    /// field that already has the standard name.
+    pub fn apply(&self, manifest: &mut Value) {
+        let Some(Value::Object(data)) = manifest.get_mut("data") else {
+            return;
+        };
+        for entry in data.values_mut() {
+            let Value::Object(fields) = entry else {
+                continue;
+            };
+            for (from, to) in &self.renames {
+                if let Some(value) = fields.remove(from) {
+                    fields.insert(to.clone(), value);
+                }
+            }
+        }
+    }
+}
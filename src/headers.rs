@@ -0,0 +1,134 @@
+use anyhow::{bail, Context, Result};
+use std::{collections::HashMap, iter::Peekable, str::Chars};
+
+/// Headers that are left to the HTTP client even if a pasted request sets
+/// them: copying a browser's `Accept-Encoding` would ask for encodings the
+/// client cannot decode.
+const CLIENT_MANAGED_HEADERS: [&str; 4] =
+    ["accept-encoding", "connection", "content-length", "host"];
+
+/// Extra request headers sent with every request, e.g. for authenticated
+/// servers.
+pub struct ExtraHeaders;
+
+impl ExtraHeaders {
+    /// Pull the headers out of a request copied from browser DevTools with
+    /// "Copy as cURL", e.g. `curl 'https://…' -H 'authorization: Bearer …'`.
+    /// Both `-H`/`--header` and `-b`/`--cookie` are read; everything else on
+    /// the command line is ignored.
+    pub fn from_curl_command(input: &str) -> Result<HashMap<String, String>> {
+        let words = shell_words(input)?;
+        let mut words = words.iter();
+        if words.next().map(String::as_str) != Some("curl") {
+            bail!("Expected a command starting with `curl`");
+        }
+        let mut headers = HashMap::new();
+        while let Some(word) = words.next() {
+            match word.as_str() {
+                "-H" | "--header" => {
+                    let header = words.next().context("`-H` is missing its header")?;
+                    let Some((name, value)) = header.split_once(':') else {
+                        bail!("`{}` is not a `name: value` header", header);
+                    };
+                    let name = name.trim().to_ascii_lowercase();
+                    if !CLIENT_MANAGED_HEADERS.contains(&name.as_str()) {
+                        headers.insert(name, value.trim().to_string());
+                    }
+                }
+                "-b" | "--cookie" => {
+                    let cookies = words.next().context("`-b` is missing its cookies")?;
+                    headers.insert(String::from("cookie"), cookies.clone());
+                }
+                _ => {}
+            }
+        }
+        Ok(headers)
+    }
+}
+
+/// Split `input` into words the way a POSIX shell would, understanding the
+/// quoting DevTools produces: single and double quotes, `$'...'` strings and
+/// backslash line continuations.
+fn shell_words(input: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated single quote"),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'\'') => {
+                chars.next();
+                in_word = true;
+                ansi_c_quoted(&mut chars, &mut word)?;
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => bail!("Unterminated double quote"),
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some('\n') | None => {}
+                Some(c) => {
+                    in_word = true;
+                    word.push(c);
+                }
+            },
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// The body of a `$'...'` string, after the opening quote.
+fn ansi_c_quoted(chars: &mut Peekable<Chars>, word: &mut String) -> Result<()> {
+    loop {
+        match chars.next() {
+            Some('\'') => return Ok(()),
+            Some('\\') => match chars.next() {
+                Some('n') => word.push('\n'),
+                Some('t') => word.push('\t'),
+                Some('r') => word.push('\r'),
+                Some(c) => word.push(c),
+                None => bail!("Unterminated $'' string"),
+            },
+            Some(c) => word.push(c),
+            None => bail!("Unterminated $'' string"),
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use anyhow::{Context, Result};
+use std::{collections::HashSet, io::ErrorKind, path::Path};
+
+/// The file in the output directory listing keys that are never downloaded.
+pub const IGNORE_FILE_NAME: &str = ".panels-ignore";
+
+/// Read the keys listed in `directory`'s ignore file, one per line. Anything
+/// after a `#` is a comment, and blank lines are skipped. A missing file
+/// ignores nothing.
+pub(crate) fn read_ignored_keys(directory: &Path) -> Result<HashSet<String>> {
+    let path = directory.join(IGNORE_FILE_NAME);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let key = line.split('#').next().unwrap_or_default().trim();
+            (!key.is_empty()).then(|| key.to_string())
+        })
+        .collect())
+}
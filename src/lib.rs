@@ -0,0 +1,57 @@
+#![deny(unused_variables)]
+
+mod app;
+mod checkpoint;
+mod circuit_breaker;
+mod continuation;
+mod cookies;
+mod dedupe;
+mod error;
+mod etag_cache;
+mod field_map;
+mod headers;
+mod ignore_file;
+mod manifest;
+mod manifest_cache;
+mod manifest_stream;
+mod metrics;
+mod pid_file;
+mod progress;
+mod rate_limit;
+mod retry;
+mod robots;
+mod scaling;
+mod self_test;
+mod stats;
+mod status;
+mod summary;
+mod task;
+mod watch;
+mod writer;
+#[cfg(feature = "exif")]
+mod xmp;
+
+pub use app::{App, AppBuilder, ErrorStrategy, IpVersion, Scheduler, TlsVersion};
+pub use circuit_breaker::{CircuitBreaker, CircuitState};
+pub use continuation::DownloadContinuation;
+pub use cookies::CookieParser;
+pub use error::PanelsError;
+pub use etag_cache::{EtagCache, EtagEntry};
+pub use field_map::FieldMap;
+pub use headers::ExtraHeaders;
+pub use ignore_file::IGNORE_FILE_NAME;
+pub use manifest::{
+    ConflictResolution, Manifest, ManifestData, MergeStrategy, QualityTier, SortOrder,
+    DEFAULT_MANIFEST_SHARD, MANIFEST_DATE,
+};
+pub use manifest_cache::ManifestCache;
+pub use pid_file::PidFile;
+pub use progress::ProgressEvent;
+pub use rate_limit::TokenBucket;
+pub use retry::{HostRetryBudget, RetryPolicy, DEFAULT_RETRY_STATUS_CODES};
+pub use self_test::{health_check, HealthStatus, SelfTestStep};
+pub use stats::DirectoryStats;
+pub use summary::Summary;
+pub use task::{DownloadStrategy, DownloadTask, NamingStrategy, OutputTemplate};
+#[cfg(feature = "exif")]
+pub use xmp::XmpTag;
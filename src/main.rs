@@ -1,150 +1,689 @@
 use anyhow::{bail, Context, Result};
-use reqwest::Client;
-use serde::Deserialize;
-use std::cmp::max;
-use std::{collections::HashMap, fs::create_dir_all, path::PathBuf};
-use tokio::{fs::File, io::AsyncWriteExt, task::JoinSet};
+use clap::{builder::PossibleValuesParser, ArgAction, Parser, Subcommand, ValueEnum};
+use panels_downloader::{
+    App, ConflictResolution, DownloadStrategy, ErrorStrategy, ExtraHeaders, FieldMap, IpVersion,
+    Manifest, ManifestData, NamingStrategy, OutputTemplate, ProgressEvent, Scheduler, SortOrder,
+    TlsVersion,
+};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{warn, Level};
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct ManifestData {
-    #[serde(alias = "as")]
-    pub _as: Option<String>,
-    pub am: Option<String>,
-    pub dhd: Option<String>,
-    pub dsd: Option<String>,
-    pub e: Option<String>,
-    pub fs: Option<String>,
-    pub s: Option<String>,
-    pub wcl0: Option<String>,
-    pub wcl1: Option<String>,
-    pub wcl2: Option<String>,
-    pub wcs0: Option<String>,
-    pub wcs1: Option<String>,
-    pub wcs2: Option<String>,
-    pub wfs: Option<String>,
-    pub wft: Option<String>,
+/// Download every wallpaper listed in the panels manifest.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Log more detail: -v for progress, -vv for debugging, -vvv for everything
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Base URL of the panels server, reached over HTTPS when no scheme is given
+    #[arg(long, default_value = "localhost:8080")]
+    domain: String,
+    /// Reach a --domain without a scheme over plain HTTP, and allow http:// domains
+    /// without a deprecation warning
+    #[arg(long)]
+    insecure_http: bool,
+    /// Directory the wallpapers are saved into
+    #[arg(long, default_value_t = default_output_dir())]
+    output_dir: String,
+    /// Number of concurrent download workers
+    #[arg(long, default_value_t = 10)]
+    workers: usize,
+    /// Only connect to the server over IPv4
+    #[arg(long, conflicts_with = "connect_via_ipv6")]
+    connect_via_ipv4: bool,
+    /// Only connect to the server over IPv6
+    #[arg(long)]
+    connect_via_ipv6: bool,
+    /// Directory to fill with `<key>.jpg` symlinks to the downloaded wallpapers
+    #[arg(long)]
+    output_symlinks_dir: Option<PathBuf>,
+    /// Serve Prometheus metrics at `/metrics` on this port during the run
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// Netscape cookie jar, as `curl --cookie-jar` writes it, whose cookies are
+    /// sent with every request
+    #[arg(long)]
+    cookie_file: Option<PathBuf>,
+    /// Save cookies set by the server back to `--cookie-file` after the run
+    #[arg(long, requires = "cookie_file")]
+    save_cookies: bool,
+    /// Ask the server to gzip the manifest. The server must honour
+    /// `Accept-Encoding: gzip` and reply with `Content-Encoding: gzip`
+    #[arg(long)]
+    gzip_manifest: bool,
+    /// Permissions for downloaded files as an octal mode, e.g. 644 (Unix only)
+    #[arg(long, value_parser = parse_file_mode)]
+    file_mode: Option<u32>,
+    /// Skip remaining downloads after this many consecutive failures
+    #[arg(long)]
+    circuit_breaker_threshold: Option<u32>,
+    /// Seconds an open circuit breaker waits before probing the server again
+    #[arg(long, default_value_t = 30)]
+    circuit_breaker_reset_secs: u64,
+    /// Only download wallpapers whose manifest key starts with this prefix
+    #[arg(long)]
+    manifest_key_prefix: Option<String>,
+    /// Connect through the platform TLS library instead of rustls
+    #[cfg(feature = "native-tls")]
+    #[arg(long)]
+    use_native_tls: bool,
+    /// Only download wallpapers whose manifest key matches this regex
+    #[cfg(feature = "regex-filter")]
+    #[arg(long)]
+    manifest_key_regex: Option<regex::Regex>,
+    /// Write a panels: XMP property into downloaded JPEGs, as name=value. Repeat
+    /// for several; `auto` fills in key, source_url and download_date
+    #[cfg(feature = "exif")]
+    #[arg(long = "tag", value_name = "NAME=VALUE", value_parser = panels_downloader::XmpTag::parse)]
+    tags: Vec<panels_downloader::XmpTag>,
+    /// Fetch the manifest from this URL, or this path on --domain, instead of the
+    /// built-in one, e.g. the path update-manifest-path prints
+    #[arg(long, conflicts_with = "manifest_shards")]
+    manifest_url: Option<String>,
+    /// Comma-separated manifest shards to fetch in parallel and merge, e.g. media-1a,media-1b
+    #[arg(long, value_delimiter = ',')]
+    manifest_shards: Vec<String>,
+    /// How to handle a key that appears in more than one manifest shard
+    #[arg(long, value_enum, default_value_t = ConflictResolution::Error)]
+    shard_conflict: ConflictResolution,
+    /// Lock this pid file for the duration of the run to keep other instances out
+    #[arg(long)]
+    write_pid_file: Option<PathBuf>,
+    /// Only download wallpapers of this category (best-effort guess from the e/as/am fields)
+    #[arg(long)]
+    filter_category: Option<String>,
+    /// Directory of per-wallpaper ETags used to skip wallpapers that have not changed
+    #[arg(long)]
+    etag_cache_dir: Option<PathBuf>,
+    /// Retry a failed request this many times, waiting twice as long each time
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+    /// Comma-separated HTTP statuses to retry like network errors
+    #[arg(long, value_delimiter = ',', default_value = "500,502,503,504")]
+    retry_on: Vec<u16>,
+    /// After a successful run, point this symlink at the output directory
+    #[arg(long)]
+    output_symlink_latest: Option<PathBuf>,
+    /// Download into a YYYY-MM-DD subdirectory of the output directory
+    #[arg(long)]
+    date_subdirectory: bool,
+    /// Limit every worker to this many bytes per second
+    #[arg(long)]
+    rate_limit: Option<u64>,
+    /// How to name downloaded files
+    #[arg(long, value_enum, default_value_t = Naming::ManifestKey)]
+    naming_strategy: Naming,
+    /// File name template for `--naming-strategy template`, e.g. {key}_{basename}
+    #[arg(long, required_if_eq("naming_strategy", "template"))]
+    name_template: Option<String>,
+    /// Write files from a pool of this many blocking threads instead of inline
+    #[arg(long)]
+    parallel_writes: Option<usize>,
+    /// File holding a request copied as cURL from browser DevTools, whose
+    /// headers and cookies are sent with every request
+    #[arg(long)]
+    headers_from_curl_command: Option<PathBuf>,
+    /// After the run, list files in the output directory for keys missing from
+    /// this manifest file. Nothing is deleted without --confirm-delete, nor
+    /// with --dry-run
+    #[arg(long)]
+    delete_missing: Option<PathBuf>,
+    /// Actually delete the files found by --delete-missing
+    #[arg(long, requires = "delete_missing")]
+    confirm_delete: bool,
+    /// Seconds before idle pooled connections are closed, 0 to keep them open
+    #[arg(long, default_value_t = 90)]
+    pool_idle_timeout: u64,
+    /// Prefer the compressed wcs* web variant of each wallpaper over the original
+    #[arg(long)]
+    web_only: bool,
+    /// Only download a URL listed under several keys once, under the first key.
+    /// Pass `--deduplicate-by-url=false` to download it under every key
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, default_value_t = true,
+        default_missing_value = "true", require_equals = true, value_name = "BOOL")]
+    deduplicate_by_url: bool,
+    /// Hash every download with SHA-256 and hard link files whose content matches
+    /// an earlier download instead of storing it twice
+    #[arg(long, visible_alias = "deduplicate")]
+    deduplicate_by_content: bool,
+    /// How a download run reports itself on stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+    /// Write paths in --output-format ndjson relative to --output-dir instead
+    /// of in full
+    #[arg(long)]
+    output_relative_paths: bool,
+    /// Stop retrying a host, and fail its remaining downloads, after this many
+    /// retries to it in total
+    #[arg(long)]
+    max_retries_per_host: Option<u32>,
+    /// Seconds each download may take per megabyte of its size, for files
+    /// whose size the server announces
+    #[arg(long, value_parser = parse_seconds)]
+    timeout_per_mb: Option<Duration>,
+    /// The shortest time limit --timeout-per-mb gives a download, in seconds
+    #[arg(long, value_parser = parse_seconds, default_value = "30")]
+    min_timeout: Duration,
+    /// Json object renaming manifest fields of a non-standard server to the
+    /// standard names, e.g. {"high_def":"dhd","standard":"dsd"}
+    #[arg(long, value_parser = FieldMap::from_json)]
+    field_map: Option<FieldMap>,
+    /// Instead of fetching the manifest, wait for manifest json files to appear
+    /// in this directory and download each, moving it to `processed/` after
+    #[arg(long, conflicts_with = "delete_missing")]
+    watch_output_dir: Option<PathBuf>,
+    /// Download the newline-separated URLs read from stdin instead of the
+    /// manifest, naming each file after its position in the list from zero
+    #[arg(long, conflicts_with = "watch_output_dir")]
+    read_urls_from_stdin: bool,
+    /// How the download workers are driven
+    #[arg(long, value_enum, default_value_t = Scheduler::default())]
+    scheduler: Scheduler,
+    /// Path of each file under the output directory, with {year}, {month},
+    /// {day}, {key} and {ext} replaced, e.g. {year}/{month}/{key}.{ext}
+    #[arg(long)]
+    output_template_dir: Option<String>,
+    /// cookies.txt jar written by curl or wget whose cookies are sent with every
+    /// request
+    #[arg(long)]
+    import_curl_cookies: Option<PathBuf>,
+    /// Run at most this many downloads from one host at a time, whatever the
+    /// number of workers
+    #[arg(long)]
+    max_concurrent_per_domain: Option<usize>,
+    /// Write the URL of every file to download to this file, one per line,
+    /// before downloading
+    #[arg(long)]
+    write_url_list: Option<PathBuf>,
+    /// Only download entries whose keys are not in this checkpoint file, and
+    /// add the newly downloaded keys to it afterwards
+    #[arg(long, value_name = "CHECKPOINT_FILE")]
+    since_checkpoint: Option<PathBuf>,
+    /// Skip wallpapers that look animated (best-effort guess from the URL formats)
+    #[arg(long, conflicts_with = "only_animated")]
+    skip_animated: bool,
+    /// Only download wallpapers that look animated (best-effort guess from the URL formats)
+    #[arg(long)]
+    only_animated: bool,
+    /// After the run, save a manifest of only the successfully downloaded entries here
+    #[arg(long)]
+    output_manifest_subset: Option<PathBuf>,
+    /// Experimental: add workers, up to --max-workers, while downloads take longer
+    /// than --slow-download-secs on average, and remove them once downloads are fast
+    #[arg(long)]
+    adaptive_workers: bool,
+    /// The most workers --adaptive-workers may run [default: twice --workers]
+    #[arg(long, requires = "adaptive_workers")]
+    max_workers: Option<usize>,
+    /// Average download time above which --adaptive-workers adds a worker
+    #[arg(long, value_parser = parse_seconds, default_value = "10")]
+    slow_download_secs: Duration,
+    /// Download entries ordered by quality tier, desc for the best first
+    #[arg(long, value_enum)]
+    sort_by_quality: Option<SortOrder>,
+    /// The oldest TLS version to accept when connecting
+    #[arg(long, value_enum, default_value_t = TlsVersion::default())]
+    tls_min_version: TlsVersion,
+    /// Keep a JSON file with the progress of the run at this path
+    #[arg(long)]
+    status_file: Option<PathBuf>,
+    /// How often --status-file is rewritten, in seconds
+    #[arg(long, value_parser = parse_seconds, default_value = "5")]
+    status_interval_secs: Duration,
+    /// Print the file name and URL of every planned download instead of
+    /// downloading
+    #[arg(long, conflicts_with_all = ["watch_output_dir", "read_urls_from_stdin"])]
+    dry_run: bool,
+    /// Retry a failed manifest fetch this many times, independently of --max-retries
+    #[arg(long, default_value_t = 5)]
+    manifest_retry: u32,
+    /// Put each file under two levels of directories named after the hash of its
+    /// key, e.g. ab/cd/key.jpg, to keep directories small
+    #[arg(long)]
+    output_hashdir: bool,
+    /// What to do when a download fails
+    #[arg(long, value_enum, default_value_t = ErrorStrategy::default())]
+    on_error: ErrorStrategy,
+    /// Strip query strings and fragments from manifest URLs before using them
+    #[arg(long)]
+    normalize_urls: bool,
+    /// Abort unless the server accepts a TCP connection within this many
+    /// milliseconds, checked before any HTTP request
+    #[arg(long, value_name = "MS")]
+    connect_test_timeout: Option<u64>,
+    /// Parse the manifest while it downloads instead of buffering all of it
+    #[arg(long)]
+    stream_manifest: bool,
+    /// Abort unless robots.txt on the panels domain allows fetching the
+    /// manifest and the wallpapers it hosts
+    #[arg(long)]
+    respect_robots_txt: bool,
+    /// Cancel the downloads still running after this many seconds
+    #[arg(long, value_name = "SECS")]
+    total_timeout_secs: Option<u64>,
+    /// Open the output directory in the file manager after a successful run
+    #[arg(long)]
+    open_when_done: bool,
+    /// Do not wait for the last write of each file to finish. Slightly faster, but
+    /// write errors go unnoticed and a crash can leave truncated files
+    #[arg(long)]
+    no_flush: bool,
 }
 
-impl ManifestData {
-    fn is_wallpaper(&self) -> bool {
-        self.dhd.is_some() || self.dsd.is_some()
-    }
+/// The `--naming-strategy` choices. The template itself comes from
+/// `--name-template`.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Naming {
+    ManifestKey,
+    UrlBasename,
+    Template,
+}
 
-    fn wallpaper_url(&self) -> Option<&str> {
-        if let Some(url) = self.dhd.as_ref().or(self.dsd.as_ref()) {
-            Some(url)
+/// The `--output-format` choices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// A summary line once the run is over
+    Text,
+    /// One JSON record per line for each event, and nothing else
+    Ndjson,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print how much disk space the wallpapers would take, without downloading them
+    Estimate,
+    /// Check connectivity, the manifest and the output directory without downloading
+    SelfTest,
+    /// Print the effective configuration as JSON, with header values redacted
+    ConfigPrint,
+    /// Find the most recent dated manifest path the server has, trying today and
+    /// each day of the week before, and print it for --manifest-url
+    UpdateManifestPath,
+    /// Delete the `.tmp` files of unfinished downloads from the output directory
+    Clean {
+        /// Delete without asking first
+        #[arg(long, visible_alias = "force")]
+        yes: bool,
+    },
+    /// Print statistics about the files already in the output directory
+    Stats,
+    /// Print manifest keys instead of downloading them
+    List {
+        /// Only list entries with this manifest field set, e.g. dhd or wfs,
+        /// along with its URL
+        #[arg(long, value_parser = PossibleValuesParser::new(ManifestData::field_names()))]
+        field: Option<String>,
+        /// List at most this many entries
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+}
+
+impl Cli {
+    fn ip_version(&self) -> Option<IpVersion> {
+        if self.connect_via_ipv4 {
+            Some(IpVersion::V4)
+        } else if self.connect_via_ipv6 {
+            Some(IpVersion::V6)
         } else {
             None
         }
     }
 
-    async fn download_wallpaper(
-        &self,
-        client: &Client,
-        mut download_dir: PathBuf,
-        filename: &str,
-    ) -> Result<()> {
-        if !self.is_wallpaper() {
-            bail!("Manifest does not contain wallpaper data")
+    fn naming_strategy(&self) -> NamingStrategy {
+        match (self.naming_strategy, &self.name_template) {
+            (Naming::ManifestKey, _) => NamingStrategy::ManifestKey,
+            (Naming::UrlBasename, _) => NamingStrategy::UrlBasename,
+            (Naming::Template, template) => {
+                NamingStrategy::Template(template.clone().unwrap_or_default())
+            }
         }
-        download_dir.push(filename);
-        download_dir.set_extension("jpg");
-        let wallpaper_bytes = client
-            .get(self.wallpaper_url().unwrap())
-            .send()
-            .await
-            .context("Failed to connect to server to download wallpaper")?
-            .bytes()
-            .await
-            .context("Failed to recieve data from the server")?;
-        let mut file_handle = File::create_new(download_dir)
-            .await
-            .context("Failed to open filepath")?;
-        file_handle
-            .write_all(&wallpaper_bytes)
-            .await
-            .context("Failed to write wallpaper data to file")?;
-        file_handle
-            .flush()
-            .await
-            .context("Failed to flush file contents")?;
-        Ok(())
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct Manifest {
-    pub version: u8,
-    pub data: HashMap<String, ManifestData>,
+/// `panels-wallpapers` in the user's pictures directory, or in `~/Pictures`
+/// where the platform has no such directory. Relative to the working
+/// directory only if there is no home directory either.
+fn default_output_dir() -> String {
+    dirs::picture_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join("Pictures")))
+        .map(|pictures| pictures.join("panels-wallpapers"))
+        .unwrap_or_else(|| PathBuf::from("panels-wallpapers"))
+        .to_string_lossy()
+        .into_owned()
 }
 
-impl Manifest {
-    async fn get(domain: &str) -> Result<Self> {
-        let manifest_url = format!("{}/panels-api/data/20240916/media-1a-i-p~s", domain);
-        let response = reqwest::get(manifest_url)
-            .await
-            .context("Unable to retrieve panels manifest data")?
-            .json::<Self>()
-            .await
-            .context("Unable to parse the manifest json")?;
-        Ok(response)
-    }
+/// Ask on the terminal whether to delete `count` files.
+fn confirm(count: usize) -> Result<bool> {
+    print!("Delete {} files? [y/N] ", count);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-    fn wallpapers(&self) -> Vec<&ManifestData> {
-        self.data.values().filter(|&w| w.is_wallpaper()).collect()
-    }
+/// Parse permission bits written in octal, with or without a `0o` prefix.
+fn parse_file_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .map_err(|_| format!("`{}` is not an octal file mode such as 644", mode))
 }
 
-pub struct App {
-    panels_domain: String,
-    download_directory: PathBuf,
-    workers: usize,
+/// Parse a number of seconds, which may be fractional.
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    seconds
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("`{}` is not a number of seconds", seconds))
 }
 
-impl App {
-    fn new(panels_domain: &str, download_directory: &str, workers: usize) -> Self {
-        let workers = max(workers, 1);
-        Self {
-            panels_domain: String::from(panels_domain),
-            download_directory: PathBuf::from(download_directory),
-            workers,
-        }
+/// Print `event` as a line of JSON, with its path relative to `output_dir`
+/// when given. Per-chunk progress is left out to keep the stream readable.
+fn print_ndjson_record(mut event: ProgressEvent, output_dir: Option<&Path>) {
+    if let (ProgressEvent::DownloadComplete { path, .. }, Some(output_dir)) =
+        (&mut event, output_dir)
+    {
+        *path = relative_to(path, output_dir);
+    }
+    if matches!(
+        event,
+        ProgressEvent::DownloadStarted { .. } | ProgressEvent::DownloadProgress { .. }
+    ) {
+        return;
+    }
+    match serde_json::to_string(&event) {
+        Ok(record) => println!("{}", record),
+        Err(e) => warn!("Failed to serialize {:?}: {}", event, e),
     }
+}
 
-    async fn run(&self) -> Result<()> {
-        create_dir_all(&self.download_directory).context(
-            "Failed to make download directory. Please make sure you have write permissions",
-        )?;
-        let manifest = Manifest::get(&self.panels_domain).await?;
-        let wallpapers = manifest.wallpapers();
-        let mut wallpaper_lists = Vec::with_capacity(self.workers);
-        for _ in 0..self.workers {
-            wallpaper_lists.push(Vec::new());
-        }
-        for (i, wallpaper) in wallpapers.into_iter().enumerate() {
-            wallpaper_lists[i % self.workers].push(wallpaper.clone());
-        }
-        let mut futures: JoinSet<Result<()>> = JoinSet::new();
-        for (thread_number, wallpaper_list) in wallpaper_lists.into_iter().enumerate() {
-            let download_dir = self.download_directory.clone();
-            futures.spawn(async move {
-                let client = Client::new();
-                for (wallpaper_num, wallpaper) in wallpaper_list.into_iter().enumerate() {
-                    let filename = format!("{}_{}", thread_number, wallpaper_num);
-                    wallpaper
-                        .download_wallpaper(&client, download_dir.clone(), &filename)
-                        .await?;
-                }
-                Ok(())
+/// List the files found by --delete-missing, which are only gone if `deleted`.
+fn print_orphans(orphans: &[PathBuf], deleted: bool, text_output: bool, output_dir: Option<&Path>) {
+    for orphan in orphans {
+        if text_output {
+            let action = if deleted { "Deleted" } else { "Would delete" };
+            println!("{} {}", action, orphan.display());
+        } else {
+            let path = match output_dir {
+                Some(output_dir) => relative_to(orphan, output_dir),
+                None => orphan.clone(),
+            };
+            let record = serde_json::json!({
+                "event": "orphan",
+                "path": path,
+                "deleted": deleted,
             });
+            println!("{}", record);
         }
-        futures.join_all().await;
-        Ok(())
     }
 }
 
+/// `path` without its `output_dir` prefix, or unchanged when it lies elsewhere.
+fn relative_to(path: &Path, output_dir: &Path) -> PathBuf {
+    path.strip_prefix(output_dir)
+        .map_or_else(|_| path.to_path_buf(), Path::to_path_buf)
+}
+
+/// Log to stderr at a level picked by the number of `-v` flags. Warnings and
+/// errors are always shown.
+fn init_logging(verbosity: u8) {
+    let level = match verbosity {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let app = App::new("http://localhost:8080", "wallpapers", 10);
-    app.run().await
+    let cli = Cli::parse();
+    init_logging(cli.verbose);
+    let extra_headers = match &cli.headers_from_curl_command {
+        Some(path) => ExtraHeaders::from_curl_command(
+            &std::fs::read_to_string(path).context("Failed to read the curl command file")?,
+        )?,
+        None => HashMap::new(),
+    };
+    let builder = App::builder(&cli.domain, &cli.output_dir, cli.workers)
+        .insecure_http(cli.insecure_http)
+        .ip_version(cli.ip_version())
+        .naming_strategy(cli.naming_strategy())
+        .symlinks_directory(cli.output_symlinks_dir)
+        .metrics_port(cli.metrics_port)
+        .cookie_file(cli.cookie_file)
+        .save_cookies(cli.save_cookies)
+        .gzip_manifest(cli.gzip_manifest)
+        .file_mode(cli.file_mode)
+        .circuit_breaker_threshold(cli.circuit_breaker_threshold)
+        .circuit_breaker_reset_timeout(Duration::from_secs(cli.circuit_breaker_reset_secs))
+        .key_prefix(cli.manifest_key_prefix)
+        .manifest_url(cli.manifest_url)
+        .manifest_shards(cli.manifest_shards)
+        .shard_conflict(cli.shard_conflict)
+        .pid_file(cli.write_pid_file)
+        .category(cli.filter_category)
+        .etag_cache_dir(cli.etag_cache_dir)
+        .max_retries(cli.max_retries)
+        .retry_status_codes(cli.retry_on.into_iter().collect())
+        .latest_symlink(cli.output_symlink_latest)
+        .date_subdirectory(cli.date_subdirectory)
+        .rate_limit(cli.rate_limit)
+        .parallel_writes(cli.parallel_writes)
+        .extra_headers(extra_headers)
+        .pool_idle_timeout(
+            (cli.pool_idle_timeout > 0).then(|| Duration::from_secs(cli.pool_idle_timeout)),
+        )
+        .download_strategy(if cli.web_only {
+            DownloadStrategy::WebOnly
+        } else {
+            DownloadStrategy::HdOnly
+        })
+        .deduplicate(cli.deduplicate_by_content)
+        .deduplicate_by_url(cli.deduplicate_by_url)
+        .max_retries_per_host(cli.max_retries_per_host)
+        .timeout_per_mb(cli.timeout_per_mb)
+        .min_timeout(cli.min_timeout)
+        .field_map(cli.field_map.unwrap_or_default())
+        .scheduler(cli.scheduler)
+        .output_template(cli.output_template_dir.as_deref().map(OutputTemplate::new))
+        .import_curl_cookies(cli.import_curl_cookies)
+        .max_connections_per_host(cli.max_concurrent_per_domain)
+        .url_list(cli.write_url_list)
+        .checkpoint(cli.since_checkpoint)
+        .animated(match (cli.skip_animated, cli.only_animated) {
+            (true, _) => Some(false),
+            (_, true) => Some(true),
+            _ => None,
+        })
+        .manifest_subset(cli.output_manifest_subset)
+        .max_workers(
+            cli.adaptive_workers
+                .then(|| cli.max_workers.unwrap_or(cli.workers * 2)),
+        )
+        .slow_download_threshold(cli.slow_download_secs)
+        .quality_order(cli.sort_by_quality)
+        .tls_min_version(cli.tls_min_version)
+        .status_file(cli.status_file)
+        .status_interval(cli.status_interval_secs)
+        .manifest_max_retries(cli.manifest_retry)
+        .hash_directories(cli.output_hashdir)
+        .error_strategy(cli.on_error)
+        .normalize_urls(cli.normalize_urls)
+        .connect_test_timeout(cli.connect_test_timeout.map(Duration::from_millis))
+        .stream_manifest(cli.stream_manifest)
+        .respect_robots_txt(cli.respect_robots_txt)
+        .total_timeout(cli.total_timeout_secs.map(Duration::from_secs))
+        .open_when_done(cli.open_when_done)
+        .flush_writes(!cli.no_flush);
+    #[cfg(feature = "regex-filter")]
+    let builder = builder.key_regex(cli.manifest_key_regex);
+    #[cfg(feature = "exif")]
+    let builder = builder.xmp_tags(cli.tags);
+    #[cfg(feature = "native-tls")]
+    let builder = builder.native_tls(cli.use_native_tls);
+    builder.validate()?;
+    if let Some(Command::ConfigPrint) = cli.command {
+        serde_json::to_writer_pretty(std::io::stdout(), &builder)?;
+        println!();
+        return Ok(());
+    }
+    let mut app = builder.build();
+    let text_output = cli.output_format == OutputFormat::Text;
+    if cli.output_relative_paths && text_output {
+        bail!("--output-relative-paths only applies to --output-format ndjson");
+    }
+    let relative_paths_base = cli
+        .output_relative_paths
+        .then(|| PathBuf::from(&cli.output_dir));
+    if !text_output {
+        let output_dir = relative_paths_base.clone();
+        app.set_progress_callback(move |event| print_ndjson_record(event, output_dir.as_deref()));
+    }
+    match cli.command {
+        None => {
+            if let Some(directory) = &cli.watch_output_dir {
+                return app
+                    .watch_manifest_directory(directory, |path, summary| {
+                        if text_output {
+                            println!("{}: {}", path.display(), summary);
+                        }
+                    })
+                    .await;
+            }
+            if cli.dry_run {
+                for task in app.run_dry().await? {
+                    if text_output {
+                        println!("{}\t{}", task.filename, task.url);
+                    } else {
+                        println!("{}", serde_json::to_string(&task)?);
+                    }
+                }
+                if let Some(path) = &cli.delete_missing {
+                    let manifest = Manifest::load(path)?;
+                    let orphans = app.delete_missing(&manifest, false).await?;
+                    print_orphans(&orphans, false, text_output, relative_paths_base.as_deref());
+                }
+                return Ok(());
+            }
+            let summary = if cli.read_urls_from_stdin {
+                let urls = std::io::stdin()
+                    .lock()
+                    .lines()
+                    .map(|line| line.map(|line| line.trim().to_string()))
+                    .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("Failed to read URLs from stdin")?;
+                app.run_with_manifest(Manifest::from_urls(urls)).await?
+            } else {
+                app.run().await?
+            };
+            if text_output {
+                println!("{}", summary);
+            }
+            if let Some(path) = &cli.delete_missing {
+                let manifest = Manifest::load(path)?;
+                let orphans = app.delete_missing(&manifest, cli.confirm_delete).await?;
+                print_orphans(
+                    &orphans,
+                    cli.confirm_delete,
+                    text_output,
+                    relative_paths_base.as_deref(),
+                );
+            }
+            Ok(())
+        }
+        Some(Command::Estimate) => {
+            let total_bytes = app.estimate_total_size().await?;
+            println!(
+                "{}",
+                humansize::format_size(total_bytes, humansize::DECIMAL)
+            );
+            Ok(())
+        }
+        Some(Command::Clean { yes }) => {
+            let partial_files = app.clean_partial_files(false).await?;
+            for (path, _) in &partial_files {
+                println!("{}", path.display());
+            }
+            if partial_files.is_empty() || !(yes || confirm(partial_files.len())?) {
+                println!("Removed 0 files");
+                return Ok(());
+            }
+            let removed = app.clean_partial_files(true).await?;
+            let bytes: u64 = removed.iter().map(|(_, size)| size).sum();
+            println!(
+                "Removed {} files, freeing {}",
+                removed.len(),
+                humansize::format_size(bytes, humansize::DECIMAL)
+            );
+            Ok(())
+        }
+        Some(Command::Stats) => {
+            let manifest = app.fetch_manifest().await?;
+            let mut stats = app.directory_stats(&manifest).await?;
+            if let (Some(path), Some(output_dir)) = (&mut stats.largest_file, &relative_paths_base)
+            {
+                *path = relative_to(path, output_dir);
+            }
+            if text_output {
+                println!("{}", stats);
+            } else {
+                println!("{}", serde_json::to_string(&stats)?);
+            }
+            Ok(())
+        }
+        Some(Command::UpdateManifestPath) => match app.latest_manifest_path().await? {
+            Some(path) => {
+                println!("{}", path);
+                Ok(())
+            }
+            None => bail!("None of the recent manifest paths exist on the server"),
+        },
+        Some(Command::SelfTest) => {
+            let steps = app.self_test().await;
+            for step in &steps {
+                match &step.result {
+                    Ok(detail) => println!("[PASS] {}: {}", step.name, detail),
+                    Err(e) => println!("[FAIL] {}: {:#}\n       {}", step.name, e, step.hint),
+                }
+            }
+            let failed = steps.iter().filter(|step| !step.passed()).count();
+            if failed > 0 {
+                bail!("{} of {} self-test checks failed", failed, steps.len());
+            }
+            Ok(())
+        }
+        Some(Command::List { field, limit }) => {
+            let manifest = app.fetch_manifest().await?;
+            let limit = limit.unwrap_or(usize::MAX);
+            match field {
+                Some(field) => {
+                    for (key, data) in manifest.top_n_by_field(limit, &field) {
+                        let url = data
+                            .all_variant_urls_with_names()
+                            .find_map(|(name, url)| (name == field).then_some(url))
+                            .unwrap_or_default();
+                        println!("{}\t{}", key, url);
+                    }
+                }
+                None => {
+                    for (key, _) in manifest.wallpapers_sorted_by_key().into_iter().take(limit) {
+                        println!("{}", key);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Some(Command::ConfigPrint) => unreachable!("printed before the app is built"),
+    }
 }
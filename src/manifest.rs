@@ -0,0 +1,815 @@
+use crate::field_map::FieldMap;
+use crate::manifest_stream::{self, Item};
+use crate::retry::RetryPolicy;
+use crate::task::{DownloadStrategy, DownloadTask};
+use anyhow::{bail, Context, Result};
+use futures_util::{stream::FuturesUnordered, Stream, StreamExt, TryStreamExt};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::warn;
+
+/// How many HEAD requests [`Manifest::prefetch_sizes`] keeps in flight.
+const PREFETCH_CONCURRENCY: usize = 10;
+
+/// The manifest served by the production panels API.
+pub const DEFAULT_MANIFEST_SHARD: &str = "media-1a-i-p~s";
+
+/// The date of the panels data directory the manifest is fetched from.
+pub const MANIFEST_DATE: &str = "20240916";
+
+/// The json names of the [`ManifestData`] fields, in declaration order.
+const FIELD_NAMES: [&str; 15] = [
+    "as", "am", "dhd", "dsd", "e", "fs", "s", "wcl0", "wcl1", "wcl2", "wcs0", "wcs1", "wcs2",
+    "wfs", "wft",
+];
+
+/// The best quality an entry offers, from highest to lowest, going by which
+/// of its fields are set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum QualityTier {
+    /// A `dhd` original.
+    HighDef,
+    /// A `dsd` original but no `dhd`.
+    Standard,
+    /// Only compressed `wcs*` or `wfs` web variants.
+    WebCompressed,
+    /// Only small `wcl*` or `wft` previews.
+    Thumbnail,
+    Unknown,
+}
+
+/// Which end of an ordering comes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// One entry of the manifest. The API marks no field as required and every
+/// field is optional here, but in practice an entry is one of four kinds,
+/// going by which fields it has:
+///
+/// - a wallpaper: `dhd` and `dsd`, sometimes only one of them;
+/// - a preview card: `s`, with either `wfs` or the `wcl*`, `wcs*` and `wft`
+///   set, and sometimes `fs`;
+/// - an artist: `am` and `as`, always together;
+/// - a feature banner: only `e`.
+///
+/// All URLs carry an expiry and signature in their query, and all but `dhd`
+/// are resized by the CDN to the `w`×`h` and `fm` format given there.
+///
+/// Two entries are equal when every URL field matches.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ManifestData {
+    /// The small artist avatar, 98×98. Set on artist entries only, along
+    /// with `am`.
+    #[serde(alias = "as", rename(serialize = "as"))]
+    pub _as: Option<String>,
+    /// The medium artist avatar, 144×144. Set on artist entries only.
+    pub am: Option<String>,
+    /// The wallpaper at its original size and format. Set on almost every
+    /// wallpaper entry. Also read from the names other API variants use for
+    /// the original.
+    #[serde(
+        alias = "high_def",
+        alias = "high_def_url",
+        alias = "hd",
+        alias = "wallpaper_hd",
+        alias = "wallpaper_url"
+    )]
+    pub dhd: Option<String>,
+    /// The wallpaper cropped to 1080×1920 jpg. Set on almost every wallpaper
+    /// entry.
+    #[serde(alias = "standard_def_url", alias = "sd")]
+    pub dsd: Option<String>,
+    /// A feature banner, 844×844. The only field of banner entries.
+    pub e: Option<String>,
+    /// A tall full-screen preview jpg, 800 wide. On some preview entries.
+    pub fs: Option<String>,
+    /// The main preview avif, 800 wide. Set on every preview entry.
+    pub s: Option<String>,
+    /// Wide collection banners, large to small. Set together with the
+    /// `wcs*` variants and `wft` on collection previews.
+    pub wcl0: Option<String>,
+    pub wcl1: Option<String>,
+    pub wcl2: Option<String>,
+    /// Compressed web variants, large to small, from 360 wide. Set on
+    /// collection previews.
+    pub wcs0: Option<String>,
+    pub wcs1: Option<String>,
+    pub wcs2: Option<String>,
+    /// The compressed web preview, 360×570. Set on single previews instead
+    /// of the `wc*` variants.
+    pub wfs: Option<String>,
+    /// The square web thumbnail, 380×380. Set on collection previews.
+    pub wft: Option<String>,
+}
+
+impl ManifestData {
+    pub fn is_wallpaper(&self) -> bool {
+        self.dhd.is_some() || self.dsd.is_some()
+    }
+
+    /// Whether any URL field is set, i.e. there is something to download.
+    pub fn has_any_url(&self) -> bool {
+        self.url_fields().into_iter().any(Option::is_some)
+    }
+
+    /// Whether the entry only has web variants: no `dhd` or `dsd` original,
+    /// but at least one `wcs*` URL.
+    pub fn is_web_only(&self) -> bool {
+        !self.is_wallpaper() && self.web_wallpaper_url().is_some()
+    }
+
+    /// The first of the compressed `wcs0`, `wcs1` and `wcs2` variants.
+    pub fn web_wallpaper_url(&self) -> Option<&str> {
+        self.wcs0
+            .as_deref()
+            .or(self.wcs1.as_deref())
+            .or(self.wcs2.as_deref())
+    }
+
+    pub fn wallpaper_url(&self) -> Option<&str> {
+        if let Some(url) = self.dhd.as_ref().or(self.dsd.as_ref()) {
+            Some(url)
+        } else {
+            None
+        }
+    }
+
+    /// Best-effort guess at what kind of entry this is, taken from the first
+    /// of `e`, `as` and `am` that is set. The panels CDN files those under
+    /// `/static/<category>/...`, so that directory name is returned, e.g.
+    /// `feature-banner` or `artists`.
+    pub fn category(&self) -> Option<&str> {
+        let url = self
+            .e
+            .as_deref()
+            .or(self._as.as_deref())
+            .or(self.am.as_deref())?;
+        let path = url.split_once("://").map_or(url, |(_, rest)| rest);
+        let mut segments = path.split(['/', '?']).skip(1);
+        match segments.next()? {
+            "static" => segments.next(),
+            segment => Some(segment),
+        }
+        .filter(|segment| !segment.is_empty())
+    }
+
+    /// The best [`QualityTier`] the entry's populated fields offer.
+    pub fn preferred_quality_tier(&self) -> QualityTier {
+        if self.dhd.is_some() {
+            QualityTier::HighDef
+        } else if self.dsd.is_some() {
+            QualityTier::Standard
+        } else if self.web_wallpaper_url().is_some() || self.wfs.is_some() {
+            QualityTier::WebCompressed
+        } else if [&self.wcl0, &self.wcl1, &self.wcl2, &self.wft]
+            .into_iter()
+            .any(Option::is_some)
+        {
+            QualityTier::Thumbnail
+        } else {
+            QualityTier::Unknown
+        }
+    }
+
+    /// Best-effort guess at whether the entry is animated: one of its
+    /// downloadable URLs names a `gif`, `mp4`, `webm` or `mov` format. `avif`
+    /// is not counted, since the CDN uses it for static previews too. The
+    /// `wft` field is left out for the same reason: in the manifests seen so
+    /// far it is a still `avif` thumbnail rather than a format flag. Expect to
+    /// tune this as animated entries show up.
+    pub fn is_animated(&self) -> bool {
+        [
+            &self.dhd, &self.dsd, &self.wcs0, &self.wcs1, &self.wcs2, &self.wfs,
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|url| url_format(url))
+        .any(|format| matches!(format.as_str(), "gif" | "mp4" | "webm" | "mov"))
+    }
+
+    /// The name in the manifest json of every field, in declaration order.
+    pub const fn field_names() -> &'static [&'static str] {
+        &FIELD_NAMES
+    }
+
+    /// Every populated URL field, paired with its name in the manifest json.
+    pub fn all_variant_urls_with_names(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        FIELD_NAMES
+            .into_iter()
+            .zip(self.url_fields())
+            .filter_map(|(name, url)| url.as_deref().map(|url| (name, url)))
+    }
+
+    /// Fill every field that is `None` here from `new`. With `replace`, fields
+    /// set in `new` also overwrite the ones already set here.
+    pub fn upgrade(&mut self, new: &ManifestData, replace: bool) {
+        for (field, new_field) in self.url_fields_mut().into_iter().zip(new.url_fields()) {
+            if new_field.is_some() && (replace || field.is_none()) {
+                field.clone_from(new_field);
+            }
+        }
+    }
+
+    /// Strip the query string and fragment from every URL field, so that URLs
+    /// differing only in tokens compare equal. Fields that are not URLs are
+    /// left alone. The panels CDN picks the image format from `fm` and checks
+    /// a signature in the query, so normalized URLs may no longer download.
+    pub fn normalize_urls(&mut self) {
+        for field in self.url_fields_mut().into_iter().flatten() {
+            if let Ok(mut url) = Url::parse(field) {
+                url.set_query(None);
+                url.set_fragment(None);
+                *field = url.into();
+            }
+        }
+    }
+
+    /// Every URL field in declaration order.
+    fn url_fields(&self) -> [&Option<String>; FIELD_NAMES.len()] {
+        [
+            &self._as, &self.am, &self.dhd, &self.dsd, &self.e, &self.fs, &self.s, &self.wcl0,
+            &self.wcl1, &self.wcl2, &self.wcs0, &self.wcs1, &self.wcs2, &self.wfs, &self.wft,
+        ]
+    }
+
+    /// [`ManifestData::url_fields`], mutably.
+    fn url_fields_mut(&mut self) -> [&mut Option<String>; FIELD_NAMES.len()] {
+        [
+            &mut self._as,
+            &mut self.am,
+            &mut self.dhd,
+            &mut self.dsd,
+            &mut self.e,
+            &mut self.fs,
+            &mut self.s,
+            &mut self.wcl0,
+            &mut self.wcl1,
+            &mut self.wcl2,
+            &mut self.wcs0,
+            &mut self.wcs1,
+            &mut self.wcs2,
+            &mut self.wfs,
+            &mut self.wft,
+        ]
+    }
+
+    /// Plan the files `strategy` would download for the entry stored under `key`.
+    pub fn to_download_tasks(&self, key: &str, strategy: DownloadStrategy) -> Vec<DownloadTask> {
+        match strategy {
+            DownloadStrategy::HdOnly => self
+                .wallpaper_url()
+                .map(|url| download_task(key, key, url))
+                .into_iter()
+                .collect(),
+            DownloadStrategy::WebOnly => self
+                .web_wallpaper_url()
+                .or(self.wallpaper_url())
+                .map(|url| download_task(key, key, url))
+                .into_iter()
+                .collect(),
+            DownloadStrategy::AllVariants => self
+                .all_variant_urls_with_names()
+                .map(|(name, url)| download_task(key, &format!("{}_{}", key, name), url))
+                .collect(),
+            DownloadStrategy::WebVariantsOnly => self
+                .all_variant_urls_with_names()
+                .filter(|(name, _)| name.starts_with('w'))
+                .map(|(name, url)| download_task(key, &format!("{}_{}", key, name), url))
+                .collect(),
+        }
+    }
+}
+
+/// Build an entry from an untyped json object. Unknown fields are ignored so
+/// newer manifests keep working; known fields must be strings or null.
+impl TryFrom<&HashMap<String, Value>> for ManifestData {
+    type Error = anyhow::Error;
+
+    fn try_from(fields: &HashMap<String, Value>) -> Result<Self> {
+        let field = |name: &str| -> Result<Option<String>> {
+            match fields.get(name) {
+                None | Some(Value::Null) => Ok(None),
+                Some(Value::String(url)) => Ok(Some(url.clone())),
+                Some(other) => bail!(
+                    "Manifest field {} should be a string, found {}",
+                    name,
+                    other
+                ),
+            }
+        };
+        let mut data = Self::default();
+        for (name, slot) in FIELD_NAMES.into_iter().zip(data.url_fields_mut()) {
+            *slot = field(name)?;
+        }
+        if data._as.is_none() {
+            data._as = field("_as")?;
+        }
+        Ok(data)
+    }
+}
+
+fn download_task(key: &str, stem: &str, url: &str) -> DownloadTask {
+    let (extension, content_type) = image_format(url);
+    DownloadTask {
+        key: key.to_string(),
+        url: url.to_string(),
+        filename: format!("{}.{}", stem, extension),
+        expected_content_type: content_type,
+    }
+}
+
+/// A single attempt, as the manifest helpers outside [`App`](crate::App) make.
+fn no_retries() -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 0,
+        ..RetryPolicy::default()
+    }
+}
+
+/// Where the manifest stored under `shard` is served from.
+pub(crate) fn shard_url(domain: &str, shard: &str) -> String {
+    format!("{}{}", domain, manifest_path(MANIFEST_DATE, shard))
+}
+
+/// The path of `shard` in the data directory of `date`, written `YYYYMMDD`.
+pub(crate) fn manifest_path(date: &str, shard: &str) -> String {
+    format!("/panels-api/data/{}/{}", date, shard)
+}
+
+/// Guess the extension and content type the server will answer `url` with.
+/// The panels CDN transcodes on the fly, so the `fm` query parameter wins
+/// over the extension in the path.
+fn image_format(url: &str) -> (&'static str, Option<&'static str>) {
+    match url_format(url).as_deref() {
+        Some("png") => ("png", Some("image/png")),
+        Some("webp") => ("webp", Some("image/webp")),
+        Some("avif") => ("avif", Some("image/avif")),
+        Some("gif") => ("gif", Some("image/gif")),
+        Some("jpg" | "jpeg") => ("jpg", Some("image/jpeg")),
+        _ => ("jpg", None),
+    }
+}
+
+/// The lowercased format named by `url`'s `fm` query parameter, or else by
+/// the extension of its path.
+fn url_format(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    url.query_pairs()
+        .find(|(name, _)| name == "fm")
+        .map(|(_, value)| value.into_owned())
+        .or_else(|| {
+            url.path()
+                .rsplit_once('.')
+                .map(|(_, extension)| extension.to_string())
+        })
+        .map(|format| format.to_ascii_lowercase())
+}
+
+/// How [`Manifest::merge`] treats a key present in both manifests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, clap::ValueEnum)]
+pub enum ConflictResolution {
+    KeepFirst,
+    KeepLast,
+    #[default]
+    Error,
+}
+
+/// How [`Manifest::get_all_domains`] combines the manifests of several
+/// domains, e.g. mirrors or shards of one deployment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeStrategy {
+    /// Keep the entry of the domain listed first.
+    PreferFirst,
+    /// Keep the entry of the domain listed last.
+    PreferLast,
+    /// Fail on a key listed by more than one domain.
+    #[default]
+    ErrorOnConflict,
+    /// Keep every entry. A key listed by more than one domain is suffixed
+    /// with the index of each domain, e.g. `key-0` and `key-1`.
+    UnionAll,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Manifest {
+    pub version: u8,
+    pub data: HashMap<String, ManifestData>,
+}
+
+impl Manifest {
+    pub async fn get(domain: &str) -> Result<Self> {
+        Self::get_with_client(domain, &Client::new()).await
+    }
+
+    /// Like [`Manifest::get`], but sent with `client`, so the fetch uses the
+    /// same proxy, headers and other settings as the downloads.
+    pub async fn get_with_client(domain: &str, client: &Client) -> Result<Self> {
+        Self::fetch_from(
+            &shard_url(domain, DEFAULT_MANIFEST_SHARD),
+            client,
+            &no_retries(),
+            &FieldMap::default(),
+        )
+        .await
+    }
+
+    /// Like [`Manifest::get_with_client`], but the entries are parsed one at
+    /// a time as the json arrives and handed out as a stream, so the whole
+    /// body is never held in memory. A parse error ends the stream after
+    /// the entries before it. Must be called within a Tokio runtime.
+    pub fn get_via_stream(
+        domain: &str,
+        client: &Client,
+    ) -> impl Stream<Item = Result<(String, ManifestData)>> {
+        let url = shard_url(domain, DEFAULT_MANIFEST_SHARD);
+        Self::stream_from(&url, client, &no_retries()).try_filter_map(|item| async move {
+            Ok(match item {
+                Item::Entry(key, data) => Some((key, *data)),
+                Item::Version(_) => None,
+            })
+        })
+    }
+
+    /// Collect the items of [`Manifest::stream_from`] into a manifest as they
+    /// arrive, holding on only to the entries `keep` accepts. Fails on the
+    /// first error, and like the buffered parser when there is no version.
+    pub(crate) async fn from_stream(
+        items: impl Stream<Item = Result<Item>>,
+        keep: impl Fn(&str, &ManifestData) -> bool,
+    ) -> Result<Self> {
+        let mut items = std::pin::pin!(items);
+        let mut version = None;
+        let mut data = HashMap::new();
+        while let Some(item) = items.try_next().await? {
+            match item {
+                Item::Version(parsed) => version = Some(parsed),
+                Item::Entry(key, entry) => {
+                    if keep(&key, &entry) {
+                        data.insert(key, *entry);
+                    }
+                }
+            }
+        }
+        let version =
+            version.context("Unable to parse the manifest json: missing field `version`")?;
+        Ok(Self { version, data })
+    }
+
+    /// Fetch the manifests of all `domains` at once and combine them with
+    /// `merge`. The version of the first domain's manifest is kept.
+    pub async fn get_all_domains(domains: &[&str], merge: MergeStrategy) -> Result<Self> {
+        let client = Client::new();
+        let mut fetches: FuturesUnordered<_> = domains
+            .iter()
+            .enumerate()
+            .map(|(i, domain)| {
+                let client = &client;
+                async move {
+                    Self::get_with_client(domain, client)
+                        .await
+                        .map(|manifest| (i, manifest))
+                        .with_context(|| format!("Failed to fetch the manifest of {}", domain))
+                }
+            })
+            .collect();
+        let mut manifests = Vec::with_capacity(domains.len());
+        while let Some(fetched) = fetches.next().await {
+            manifests.push(fetched?);
+        }
+        // They finish in any order, but the strategies go by domain order.
+        manifests.sort_unstable_by_key(|(i, _)| *i);
+        let mut manifests = manifests.into_iter().map(|(_, manifest)| manifest);
+        let Some(first) = manifests.next() else {
+            bail!("No domains to fetch manifests from");
+        };
+        let resolution = match merge {
+            MergeStrategy::PreferFirst => ConflictResolution::KeepFirst,
+            MergeStrategy::PreferLast => ConflictResolution::KeepLast,
+            MergeStrategy::ErrorOnConflict => ConflictResolution::Error,
+            MergeStrategy::UnionAll => return Self::union_all(first, manifests),
+        };
+        manifests.try_fold(first, |merged, manifest| merged.merge(manifest, resolution))
+    }
+
+    /// Combine `first` and `rest`, suffixing every key that more than one of
+    /// them lists with the index of the manifest it came from.
+    fn union_all(first: Manifest, rest: impl Iterator<Item = Manifest>) -> Result<Self> {
+        let version = first.version;
+        let manifests: Vec<_> = std::iter::once(first).chain(rest).collect();
+        let mut listings: HashMap<&str, usize> = HashMap::new();
+        for manifest in &manifests {
+            for key in manifest.data.keys() {
+                *listings.entry(key).or_default() += 1;
+            }
+        }
+        let shared: HashSet<String> = listings
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(key, _)| key.to_string())
+            .collect();
+        let mut data = HashMap::new();
+        for (i, manifest) in manifests.into_iter().enumerate() {
+            for (key, entry) in manifest.data {
+                let key = if shared.contains(&key) {
+                    format!("{}-{}", key, i)
+                } else {
+                    key
+                };
+                match data.entry(key) {
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(entry);
+                    }
+                    Entry::Occupied(occupied) => bail!(
+                        "Manifest key {} is listed both as is and as a suffixed duplicate",
+                        occupied.key()
+                    ),
+                }
+            }
+        }
+        Ok(Self { version, data })
+    }
+
+    /// The entries of the manifest at `domain` whose keys sort between
+    /// `from_key` and `to_key`, both included. The panels API has no range
+    /// query, so for now the whole manifest is fetched and filtered here.
+    pub async fn get_range(domain: &str, from_key: &str, to_key: &str) -> Result<Self> {
+        Ok(Self::get(domain).await?.key_range(from_key, to_key))
+    }
+
+    /// A manifest with one wallpaper per URL, e.g. URLs collected elsewhere,
+    /// keyed by their position in `urls` counting from zero.
+    pub fn from_urls(urls: impl IntoIterator<Item = String>) -> Self {
+        let data = urls
+            .into_iter()
+            .enumerate()
+            .map(|(i, url)| {
+                let data = ManifestData {
+                    dhd: Some(url),
+                    ..ManifestData::default()
+                };
+                (i.to_string(), data)
+            })
+            .collect();
+        Self { version: 1, data }
+    }
+
+    /// Read a manifest saved to disk in the json format the API serves.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).context("Failed to open manifest file")?;
+        serde_json::from_reader(BufReader::new(file)).context("Unable to parse the manifest json")
+    }
+
+    /// Write the manifest to `path` as json that [`Manifest::load`] reads back.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut writer =
+            BufWriter::new(File::create(path).context("Failed to create manifest file")?);
+        serde_json::to_writer(&mut writer, self).context("Failed to write the manifest json")?;
+        writer.flush().context("Failed to write the manifest json")
+    }
+
+    /// Fetch the manifest at `url`, such as a [`shard_url`], retried
+    /// according to `retry_policy`, renaming the fields of its entries with
+    /// `field_map`.
+    pub(crate) async fn fetch_from(
+        url: &str,
+        client: &Client,
+        retry_policy: &RetryPolicy,
+        field_map: &FieldMap,
+    ) -> Result<Self> {
+        let response = retry_policy
+            .send(client.get(url))
+            .await
+            .context("Unable to retrieve panels manifest data")?;
+        if field_map.is_empty() {
+            return response
+                .json::<Self>()
+                .await
+                .context("Unable to parse the manifest json");
+        }
+        let mut json = response
+            .json::<Value>()
+            .await
+            .context("Unable to parse the manifest json")?;
+        field_map.apply(&mut json);
+        serde_json::from_value(json).context("Unable to parse the manifest json")
+    }
+
+    /// Stream the version and entries of the manifest at `url`, see
+    /// [`Manifest::get_via_stream`]. Field maps need the whole json, so none
+    /// is applied.
+    pub(crate) fn stream_from(
+        url: &str,
+        client: &Client,
+        retry_policy: &RetryPolicy,
+    ) -> impl Stream<Item = Result<Item>> {
+        let request = client.get(url);
+        let retry_policy = retry_policy.clone();
+        manifest_stream::stream_items(async move {
+            retry_policy
+                .send(request)
+                .await
+                .context("Unable to retrieve panels manifest data")
+        })
+    }
+
+    /// Combine the entries of two manifests, e.g. shards of one larger manifest.
+    /// The version of `self` is kept.
+    pub fn merge(mut self, other: Manifest, resolution: ConflictResolution) -> Result<Manifest> {
+        for (key, data) in other.data {
+            match self.data.entry(key) {
+                Entry::Vacant(entry) => {
+                    entry.insert(data);
+                }
+                Entry::Occupied(mut entry) => match resolution {
+                    ConflictResolution::KeepFirst => {}
+                    ConflictResolution::KeepLast => {
+                        entry.insert(data);
+                    }
+                    ConflictResolution::Error => {
+                        bail!(
+                            "Manifest key {} appears in more than one manifest",
+                            entry.key()
+                        )
+                    }
+                },
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn wallpapers(&self) -> Vec<(&String, &ManifestData)> {
+        self.data.iter().filter(|(_, w)| w.is_wallpaper()).collect()
+    }
+
+    /// HEAD every wallpaper URL and map each key to the `Content-Length` the
+    /// server reported, or `None` when it reported none or the request
+    /// failed. Callers can keep the map to filter or estimate by size before
+    /// any download starts.
+    pub async fn prefetch_sizes(&self, client: &Client) -> HashMap<String, Option<u64>> {
+        self.prefetch_sizes_with(client, &RetryPolicy::default(), PREFETCH_CONCURRENCY)
+            .await
+    }
+
+    /// [`Manifest::prefetch_sizes`] with at most `concurrency` requests in
+    /// flight, each retried according to `retry_policy`.
+    pub(crate) async fn prefetch_sizes_with(
+        &self,
+        client: &Client,
+        retry_policy: &RetryPolicy,
+        concurrency: usize,
+    ) -> HashMap<String, Option<u64>> {
+        let retry_policy = Arc::new(retry_policy.clone());
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut requests = JoinSet::new();
+        for task in self.to_download_queue(DownloadStrategy::HdOnly) {
+            let client = client.clone();
+            let semaphore = Arc::clone(&semaphore);
+            let retry_policy = Arc::clone(&retry_policy);
+            requests.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let size = task.content_length(&client, &retry_policy).await;
+                (task.key, size)
+            });
+        }
+        let mut sizes = HashMap::new();
+        while let Some(result) = requests.join_next().await {
+            match result {
+                Ok((key, Ok(size))) => {
+                    sizes.insert(key, size);
+                }
+                Ok((key, Err(e))) => {
+                    warn!("Failed to size wallpaper {}: {:#}", key, e);
+                    sizes.insert(key, None);
+                }
+                Err(e) => warn!("Size request panicked: {}", e),
+            }
+        }
+        sizes
+    }
+
+    /// [`ManifestData::normalize_urls`] for every entry.
+    pub fn normalize_urls(&mut self) {
+        self.data
+            .values_mut()
+            .for_each(ManifestData::normalize_urls);
+    }
+
+    /// Remove the entries without any URL, see [`ManifestData::has_any_url`],
+    /// and release the memory they took. Returns how many were removed.
+    pub fn shrink_to_fit(&mut self) -> usize {
+        let before = self.data.len();
+        self.data.retain(|_, data| data.has_any_url());
+        self.data.shrink_to_fit();
+        before - self.data.len()
+    }
+
+    /// A copy of the manifest holding only the entries `predicate` accepts.
+    /// Calls can be chained to compose filters.
+    pub fn filter_by<F: Fn(&str, &ManifestData) -> bool>(&self, predicate: F) -> Manifest {
+        Manifest {
+            version: self.version,
+            data: self
+                .data
+                .iter()
+                .filter(|(key, data)| predicate(key, data))
+                .map(|(key, data)| (key.clone(), data.clone()))
+                .collect(),
+        }
+    }
+
+    /// A copy of the manifest holding only the entries whose keys sort
+    /// between `from_key` and `to_key`, both included.
+    pub fn key_range(&self, from_key: &str, to_key: &str) -> Manifest {
+        self.filter_by(|key, _| (from_key..=to_key).contains(&key))
+    }
+
+    /// Plan every file `strategy` asks for, in key order, before any download
+    /// starts.
+    pub fn to_download_queue(&self, strategy: DownloadStrategy) -> VecDeque<DownloadTask> {
+        let mut entries: Vec<_> = self.data.iter().collect();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        entries
+            .into_iter()
+            .flat_map(|(key, data)| data.to_download_tasks(key, strategy))
+            .collect()
+    }
+
+    /// Up to `n` entries, in key order, whose `field` (named as in the
+    /// manifest json, e.g. `dhd` or `as`) is set. Handy for sampling the
+    /// structure of a large manifest.
+    pub fn top_n_by_field(&self, n: usize, field: &str) -> Vec<(&str, &ManifestData)> {
+        let mut entries: Vec<_> = self
+            .data
+            .iter()
+            .filter(|(_, data)| {
+                data.all_variant_urls_with_names()
+                    .any(|(name, _)| name == field)
+            })
+            .map(|(key, data)| (key.as_str(), data))
+            .collect();
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        entries.truncate(n);
+        entries
+    }
+
+    /// Like [`Manifest::wallpapers`], but in a stable order.
+    pub fn wallpapers_sorted_by_key(&self) -> Vec<(&String, &ManifestData)> {
+        let mut wallpapers = self.wallpapers();
+        wallpapers.sort_unstable_by_key(|(key, _)| *key);
+        wallpapers
+    }
+
+    /// Write every key of the manifest to `writer` as a sorted json array,
+    /// without the entries themselves.
+    pub fn serialize_keys_to_writer<W: Write>(&self, writer: W) -> Result<()> {
+        let mut keys: Vec<_> = self.data.keys().collect();
+        keys.sort_unstable();
+        serde_json::to_writer(writer, &keys).context("Failed to write the manifest keys")
+    }
+}
+
+/// How many keys the [`Display`](fmt::Display) summary of a manifest lists.
+const DISPLAYED_KEYS: usize = 5;
+
+/// A one-line summary: `Manifest v1: 247 entries, 215 wallpapers (showing
+/// first 5): key1, key2, key3, key4, key5, ...`, with keys in sorted order.
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Manifest v{}: {} entries, {} wallpapers",
+            self.version,
+            self.data.len(),
+            self.wallpapers().len()
+        )?;
+        if self.data.is_empty() {
+            return Ok(());
+        }
+        let mut keys: Vec<_> = self.data.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        let shown = keys.len().min(DISPLAYED_KEYS);
+        write!(
+            f,
+            " (showing first {}): {}",
+            shown,
+            keys[..shown].join(", ")
+        )?;
+        if keys.len() > shown {
+            write!(f, ", ...")?;
+        }
+        Ok(())
+    }
+}
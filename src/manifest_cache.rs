@@ -0,0 +1,90 @@
+use crate::manifest::{shard_url, Manifest, DEFAULT_MANIFEST_SHARD};
+use anyhow::{Context, Result};
+use reqwest::{
+    header::{ETAG, IF_NONE_MATCH},
+    Client, StatusCode,
+};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// The manifest kept in memory between runs that would otherwise fetch it
+/// every time, e.g. on each tick of a watch loop. It is only asked for again
+/// once `ttl` has passed, and then conditionally on its `ETag`, so the json
+/// is only downloaded and parsed again when the server reports a change.
+#[derive(Debug)]
+pub struct ManifestCache {
+    cached: Option<CachedManifest>,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct CachedManifest {
+    manifest: Manifest,
+    fetched_at: Instant,
+    etag: Option<String>,
+}
+
+impl ManifestCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self { cached: None, ttl }
+    }
+
+    /// The manifest at `domain`, fetched with `client` unless the cached one
+    /// is younger than the TTL or the server answers `304 Not Modified`.
+    pub async fn get_or_refresh(&mut self, domain: &str, client: &Client) -> Result<&Manifest> {
+        if self
+            .cached
+            .as_ref()
+            .is_some_and(|cached| cached.fetched_at.elapsed() < self.ttl)
+        {
+            debug!("Using the cached manifest");
+        } else {
+            self.refresh(domain, client).await?;
+        }
+        Ok(&self
+            .cached
+            .as_ref()
+            .expect("a manifest was cached")
+            .manifest)
+    }
+
+    async fn refresh(&mut self, domain: &str, client: &Client) -> Result<()> {
+        let mut request = client.get(shard_url(domain, DEFAULT_MANIFEST_SHARD));
+        if let Some(etag) = self
+            .cached
+            .as_ref()
+            .and_then(|cached| cached.etag.as_deref())
+        {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request
+            .send()
+            .await
+            .context("Unable to retrieve panels manifest data")?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = &mut self.cached {
+                debug!("The manifest has not changed");
+                cached.fetched_at = Instant::now();
+                return Ok(());
+            }
+        }
+        let response = response
+            .error_for_status()
+            .context("The server refused the manifest request")?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        let manifest = response
+            .json()
+            .await
+            .context("Unable to parse the manifest json")?;
+        self.cached = Some(CachedManifest {
+            manifest,
+            fetched_at: Instant::now(),
+            etag,
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use crate::manifest::ManifestData;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures_util::{stream, Stream};
+use reqwest::Response;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+use std::{
+    fmt,
+    future::Future,
+    io::{self, Read},
+};
+use tokio::sync::mpsc;
+
+/// How many chunks of the body may wait for the parser.
+const CHUNK_BUFFER: usize = 16;
+/// How many parsed entries may wait for the consumer of the stream.
+const ENTRY_BUFFER: usize = 64;
+
+/// A part of the manifest the parser has finished with.
+pub(crate) enum Item {
+    Version(u8),
+    Entry(String, Box<ManifestData>),
+}
+
+type Parsed = Result<Item>;
+
+/// The version and entries of the manifest `response` returns, parsed on the
+/// blocking thread pool while the body is still arriving. Every other field
+/// is skipped. Parsing stops at the first error, which ends the stream, and
+/// when the stream is dropped.
+pub(crate) fn stream_items(
+    response: impl Future<Output = Result<Response>> + Send + 'static,
+) -> impl Stream<Item = Parsed> {
+    let (entries, receiver) = mpsc::channel(ENTRY_BUFFER);
+    tokio::spawn(async move {
+        let mut response = match response.await {
+            Ok(response) => response,
+            Err(e) => {
+                let _ = entries.send(Err(e)).await;
+                return;
+            }
+        };
+        let (chunks, chunk_receiver) = mpsc::channel(CHUNK_BUFFER);
+        let parser_entries = entries.clone();
+        let parser = tokio::task::spawn_blocking(move || {
+            parse(ChannelReader::new(chunk_receiver), &parser_entries)
+        });
+        loop {
+            let chunk = match response.chunk().await {
+                Ok(Some(chunk)) => Ok(chunk),
+                Ok(None) => break,
+                Err(e) => Err(io::Error::other(e)),
+            };
+            let failed = chunk.is_err();
+            // A closed channel means the parser has already given up.
+            if chunks.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+        drop(chunks);
+        if let Err(e) = parser.await {
+            let _ = entries
+                .send(Err(anyhow!(e).context("Manifest parser panicked")))
+                .await;
+        }
+    });
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|entry| (entry, receiver))
+    })
+}
+
+fn parse(reader: ChannelReader, entries: &mpsc::Sender<Parsed>) {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let parsed = deserializer
+        .deserialize_map(ManifestVisitor(entries))
+        .and_then(|()| deserializer.end());
+    // Once the stream has been dropped nobody is left to tell.
+    if let Err(e) = parsed {
+        if !entries.is_closed() {
+            let _ = entries.blocking_send(Err(
+                anyhow::Error::new(e).context("Unable to parse the manifest json")
+            ));
+        }
+    }
+}
+
+/// Blocking reads over the chunks of a body received on the async side.
+struct ChannelReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl ChannelReader {
+    fn new(chunks: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+        Self {
+            chunks,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.current.len());
+        buf[..len].copy_from_slice(&self.current.split_to(len));
+        Ok(len)
+    }
+}
+
+/// Skips over everything in the top-level object except `version` and `data`.
+struct ManifestVisitor<'a>(&'a mpsc::Sender<Parsed>);
+
+impl<'de> Visitor<'de> for ManifestVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a panels manifest")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(field) = map.next_key::<String>()? {
+            match field.as_str() {
+                "version" => send(self.0, Item::Version(map.next_value()?))?,
+                "data" => map.next_value_seed(DataVisitor(self.0))?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends each entry of `data` on as soon as it has been parsed.
+struct DataVisitor<'a>(&'a mpsc::Sender<Parsed>);
+
+impl<'de> DeserializeSeed<'de> for DataVisitor<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for DataVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of manifest entries")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some((key, data)) = map.next_entry::<String, ManifestData>()? {
+            send(self.0, Item::Entry(key, Box::new(data)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Hand `item` to the stream, failing the parse once it has been dropped.
+fn send<E: de::Error>(items: &mpsc::Sender<Parsed>, item: Item) -> Result<(), E> {
+    items
+        .blocking_send(Ok(item))
+        .map_err(|_| E::custom("the manifest stream was dropped"))
+}
@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Download counters shared between the workers and the `/metrics` endpoint.
+pub struct Metrics {
+    registry: Registry,
+    downloads: IntCounterVec,
+    bytes_downloaded: IntCounter,
+    active_workers: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let downloads = IntCounterVec::new(
+            Opts::new("panels_downloads_total", "Wallpaper downloads by outcome"),
+            &["status"],
+        )?;
+        let bytes_downloaded = IntCounter::new(
+            "panels_bytes_downloaded_total",
+            "Bytes of wallpaper data written to disk",
+        )?;
+        let active_workers =
+            IntGauge::new("panels_active_workers", "Workers currently downloading")?;
+        registry.register(Box::new(downloads.clone()))?;
+        registry.register(Box::new(bytes_downloaded.clone()))?;
+        registry.register(Box::new(active_workers.clone()))?;
+        Ok(Self {
+            registry,
+            downloads,
+            bytes_downloaded,
+            active_workers,
+        })
+    }
+
+    pub fn download_succeeded(&self, bytes: u64) {
+        self.downloads.with_label_values(&["ok"]).inc();
+        self.bytes_downloaded.inc_by(bytes);
+    }
+
+    pub fn download_failed(&self) {
+        self.downloads.with_label_values(&["failed"]).inc();
+    }
+
+    /// Count a worker as active until the returned guard is dropped.
+    pub fn worker_started(self: &Arc<Self>) -> ActiveWorker {
+        self.active_workers.inc();
+        ActiveWorker(Arc::clone(self))
+    }
+
+    fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+
+    /// Bind `port` and serve `/metrics` in the background for the rest of the process.
+    pub async fn serve(self: &Arc<Self>, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .await
+            .with_context(|| format!("Failed to bind metrics port {}", port))?;
+        let router = Router::new()
+            .route("/metrics", get(metrics_handler))
+            .with_state(Arc::clone(self));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        Ok(())
+    }
+}
+
+pub struct ActiveWorker(Arc<Metrics>);
+
+impl Drop for ActiveWorker {
+    fn drop(&mut self) {
+        self.0.active_workers.dec();
+    }
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    match metrics.render() {
+        Ok(body) => (
+            [(
+                header::CONTENT_TYPE,
+                TextEncoder::new().format_type().to_string(),
+            )],
+            body,
+        )
+            .into_response(),
+        Err(e) => (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to encode metrics: {:#}", e),
+        )
+            .into_response(),
+    }
+}
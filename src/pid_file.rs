@@ -0,0 +1,50 @@
+use crate::error::PanelsError;
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, Write},
+    path::Path,
+};
+
+/// An advisory lock on a pid file, held for as long as the value lives.
+///
+/// The file itself is left in place on release: deleting it would let a
+/// process that already opened the old file lock it alongside a newcomer.
+pub struct PidFile {
+    file: File,
+}
+
+impl PidFile {
+    /// Lock `path` and write our pid into it, failing with
+    /// [`PanelsError::AnotherInstanceRunning`] if another process holds it.
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .context("Failed to open pid file")?;
+        if file.try_lock_exclusive().is_err() {
+            let mut contents = String::new();
+            let pid = file
+                .read_to_string(&mut contents)
+                .ok()
+                .and_then(|_| contents.trim().parse().ok());
+            return Err(PanelsError::AnotherInstanceRunning(pid).into());
+        }
+        file.set_len(0).context("Failed to truncate pid file")?;
+        file.rewind().context("Failed to truncate pid file")?;
+        write!(file, "{}", std::process::id()).context("Failed to write pid file")?;
+        file.flush().context("Failed to write pid file")?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = FileExt::unlock(&self.file);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::Summary;
+use serde::Serialize;
+use std::{path::PathBuf, sync::Arc};
+
+/// Events reported to the callback registered with
+/// [`App::set_progress_callback`](crate::App::set_progress_callback).
+///
+/// Events serialize as JSON objects tagged with an `event` name, e.g.
+/// `{"event":"downloaded","key":"…","path":"…","bytes":1024}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// The manifest was fetched and lists `total` wallpapers.
+    #[serde(rename = "start")]
+    ManifestFetched {
+        total: usize,
+    },
+    DownloadStarted {
+        key: String,
+    },
+    /// `total_bytes` is only known when the server sends a `Content-Length`.
+    DownloadProgress {
+        key: String,
+        bytes_received: u64,
+        total_bytes: Option<u64>,
+    },
+    #[serde(rename = "downloaded")]
+    DownloadComplete {
+        key: String,
+        path: PathBuf,
+        bytes: u64,
+    },
+    #[serde(rename = "failed")]
+    DownloadFailed {
+        key: String,
+        error: String,
+    },
+    #[serde(rename = "complete")]
+    AllComplete {
+        summary: Summary,
+    },
+}
+
+pub(crate) type ProgressCallback = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// Hands events to the registered callback, if there is one.
+#[derive(Clone, Default)]
+pub(crate) struct Progress(Option<ProgressCallback>);
+
+impl Progress {
+    pub(crate) fn new(callback: ProgressCallback) -> Self {
+        Self(Some(callback))
+    }
+
+    pub(crate) fn emit(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.0 {
+            callback(event);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// A token bucket holding one token per byte. Spending more tokens than are
+/// available puts the bucket in debt, and [`acquire`](Self::acquire) sleeps
+/// until the refill has paid it back, so the long-run rate stays at
+/// `refill_rate` whatever the chunk sizes.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: u64,
+    tokens: f64,
+    refill_rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing `bytes_per_second`, with a one second burst.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            capacity: bytes_per_second,
+            tokens: bytes_per_second as f64,
+            refill_rate: bytes_per_second as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` tokens and return how long to wait before using them.
+    pub fn reserve(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * self.refill_rate;
+        self.tokens = (self.tokens + refilled).min(self.capacity as f64) - bytes as f64;
+        self.last_refill = now;
+        if self.tokens >= 0.0 || self.refill_rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.refill_rate)
+        }
+    }
+
+    /// Take `bytes` tokens, sleeping as long as needed to stay within the rate.
+    pub async fn acquire(&mut self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limits how many requests run against each host at once, across all
+/// workers, for servers that refuse clients opening too many connections.
+#[derive(Debug)]
+pub(crate) struct HostPermits {
+    limit: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostPermits {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            semaphores: Mutex::default(),
+        }
+    }
+
+    /// Wait until fewer than the limit of requests to `host` are running. The
+    /// request counts until the permit is dropped.
+    pub(crate) async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let semaphore = Arc::clone(
+            self.semaphores
+                .lock()
+                .unwrap()
+                .entry(host.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.limit))),
+        );
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("host semaphores are never closed")
+    }
+}
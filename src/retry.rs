@@ -0,0 +1,154 @@
+use crate::error::PanelsError;
+use anyhow::{Context, Result};
+use reqwest::{RequestBuilder, Response, Url};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::warn;
+
+/// The statuses retried unless configured otherwise: server errors that
+/// usually clear up on their own. 4xx responses are never retried by default.
+pub const DEFAULT_RETRY_STATUS_CODES: [u16; 4] = [500, 502, 503, 504];
+
+/// How often and on which failures a request is sent again, waiting twice as
+/// long after each attempt.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub status_codes: HashSet<u16>,
+    pub base_delay: Duration,
+    /// Retries allowed in total per host, shared by every request sent with
+    /// this policy and its clones.
+    pub host_budget: Option<Arc<HostRetryBudget>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            status_codes: HashSet::from(DEFAULT_RETRY_STATUS_CODES),
+            base_delay: Duration::from_millis(500),
+            host_budget: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before retry number `attempt`, counting from zero.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.base_delay.saturating_mul(2u32.saturating_pow(attempt))
+    }
+
+    /// Send `request`, retrying network errors and responses whose status is
+    /// in [`status_codes`](Self::status_codes). The status is checked before
+    /// the body is read, so a retried response is simply dropped.
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let host = request_host(&request);
+        if self
+            .host_budget
+            .as_ref()
+            .is_some_and(|b| b.exhausted(&host))
+        {
+            return Err(PanelsError::HostRetriesExhausted(host).into());
+        }
+        let mut attempt = 0;
+        loop {
+            let pending = request
+                .try_clone()
+                .context("Request body cannot be replayed")?
+                .send()
+                .await;
+            let failure = match &pending {
+                Ok(response) if self.status_codes.contains(&response.status().as_u16()) => {
+                    format!("{} answered {}", response.url(), response.status())
+                }
+                Err(e) if !e.is_builder() => format!("Request failed: {}", e),
+                _ => return Ok(pending?),
+            };
+            let budget_left = self.host_budget.as_ref().is_none_or(|b| b.spend(&host));
+            if attempt >= self.max_retries || !budget_left {
+                return Ok(pending?);
+            }
+            warn!(
+                "{}, retrying ({}/{})",
+                failure,
+                attempt + 1,
+                self.max_retries
+            );
+            tokio::time::sleep(self.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Whether a download from `url` whose body was cut off should be sent
+    /// again, as retry number `attempt` counting from zero. Waits out the
+    /// backoff first. Counts against the host's budget like any other retry.
+    pub(crate) async fn retry_incomplete(&self, url: &str, attempt: u32) -> bool {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_default();
+        let budget_left = self.host_budget.as_ref().is_none_or(|b| b.spend(&host));
+        if attempt >= self.max_retries || !budget_left {
+            return false;
+        }
+        warn!(
+            "The body of {} was cut off, retrying ({}/{})",
+            url,
+            attempt + 1,
+            self.max_retries
+        );
+        tokio::time::sleep(self.backoff(attempt)).await;
+        true
+    }
+}
+
+/// A count of the retries made to each host, failing further requests to a
+/// host once it has had more than `limit`. This puts a bound on how long a
+/// run spends on a server that is down, however many downloads it has.
+#[derive(Debug)]
+pub struct HostRetryBudget {
+    limit: u32,
+    retries_by_host: Mutex<HashMap<String, u32>>,
+}
+
+impl HostRetryBudget {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            retries_by_host: Mutex::default(),
+        }
+    }
+
+    fn exhausted(&self, host: &str) -> bool {
+        let retries_by_host = self.retries_by_host.lock().unwrap();
+        retries_by_host
+            .get(host)
+            .is_some_and(|&retries| retries > self.limit)
+    }
+
+    /// Count one more retry to `host`, returning whether it is allowed.
+    fn spend(&self, host: &str) -> bool {
+        let mut retries_by_host = self.retries_by_host.lock().unwrap();
+        let retries = retries_by_host.entry(host.to_string()).or_default();
+        *retries += 1;
+        if *retries == self.limit + 1 {
+            warn!(
+                "Made {} retries to {}, failing its remaining requests",
+                self.limit, host
+            );
+        }
+        *retries <= self.limit
+    }
+}
+
+/// The host `request` is sent to, or an empty string if it has none.
+fn request_host(request: &RequestBuilder) -> String {
+    request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .and_then(|request| request.url().host_str().map(String::from))
+        .unwrap_or_default()
+}
@@ -0,0 +1,68 @@
+use crate::error::PanelsError;
+use anyhow::{Context, Result};
+use reqwest::{Client, Url};
+use robotstxt::DefaultMatcher;
+use tracing::debug;
+
+/// The `robots.txt` of the panels domain, for checking the URLs of a run
+/// against it before they are requested.
+#[derive(Debug)]
+pub(crate) struct RobotsTxt {
+    domain: Url,
+    body: String,
+    user_agent: String,
+}
+
+impl RobotsTxt {
+    /// Fetch `/robots.txt` from `domain`. A missing file, or any other client
+    /// error, allows everything, as the robots exclusion protocol says.
+    pub(crate) async fn fetch(domain: &str, client: &Client, user_agent: &str) -> Result<Self> {
+        let domain = Url::parse(domain).context("The domain is not a valid URL")?;
+        let url = domain
+            .join("/robots.txt")
+            .context("Invalid robots.txt URL")?;
+        let response = client
+            .get(url)
+            .send()
+            .await
+            .context("Failed to connect to server to fetch robots.txt")?;
+        let body = if response.status().is_client_error() {
+            debug!(
+                "No robots.txt ({}), everything is allowed",
+                response.status()
+            );
+            String::new()
+        } else {
+            response
+                .error_for_status()
+                .context("The server refused the robots.txt request")?
+                .text()
+                .await
+                .context("Failed to read robots.txt")?
+        };
+        Ok(Self {
+            domain,
+            body,
+            user_agent: user_agent.to_string(),
+        })
+    }
+
+    /// Fail with [`PanelsError::DisallowedByRobots`] on the first of `urls`
+    /// that robots.txt does not allow. URLs on other hosts, e.g. a CDN, are
+    /// not covered by this robots.txt and always pass.
+    pub(crate) fn check<'a>(&self, urls: impl IntoIterator<Item = &'a str>) -> Result<()> {
+        for url in urls {
+            let same_origin = Url::parse(url).is_ok_and(|url| url.origin() == self.domain.origin());
+            if same_origin
+                && !DefaultMatcher::default().one_agent_allowed_by_robots(
+                    &self.body,
+                    &self.user_agent,
+                    url,
+                )
+            {
+                return Err(PanelsError::DisallowedByRobots(url.to_string()).into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use crate::task::DownloadTask;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::debug;
+
+/// How many of the latest download durations the average is taken over.
+const WINDOW: usize = 10;
+
+/// How long an idle extra worker waits before checking whether it is needed.
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// Experimental: grows the number of workers taking tasks from `base` up to
+/// `max` while downloads are slow, and shrinks it back once they are fast.
+/// Every worker is spawned up front, and those above the active count wait
+/// until they are let in, without being reported as active meanwhile.
+#[derive(Debug)]
+pub(crate) struct WorkerScaling {
+    base: usize,
+    max: usize,
+    slow_threshold: Duration,
+    active: AtomicUsize,
+    durations: Mutex<VecDeque<Duration>>,
+}
+
+impl WorkerScaling {
+    pub(crate) fn new(base: usize, max: usize, slow_threshold: Duration) -> Self {
+        Self {
+            base,
+            max,
+            slow_threshold,
+            active: AtomicUsize::new(base),
+            durations: Mutex::new(VecDeque::with_capacity(WINDOW)),
+        }
+    }
+
+    pub(crate) fn max_workers(&self) -> usize {
+        self.max
+    }
+
+    /// Record how long a download took. Once a full window averages above the
+    /// threshold one more worker is let in, below half of it one is stopped.
+    pub(crate) fn record(&self, duration: Duration) {
+        let mut durations = self.durations.lock().unwrap();
+        durations.push_back(duration);
+        if durations.len() < WINDOW {
+            return;
+        }
+        let average = durations.iter().sum::<Duration>() / durations.len() as u32;
+        durations.clear();
+        let active = self.active.load(Ordering::Relaxed);
+        let target = if average > self.slow_threshold {
+            (active + 1).min(self.max)
+        } else if average < self.slow_threshold / 2 {
+            active.saturating_sub(1).max(self.base)
+        } else {
+            active
+        };
+        if target != active {
+            debug!(
+                "Downloads average {:.1?}, scaling from {} to {} workers",
+                average, active, target
+            );
+            self.active.store(target, Ordering::Relaxed);
+        }
+    }
+
+    /// The next task for the worker numbered `index`, waiting while that
+    /// worker is above the active count. `None` once the queue is empty.
+    /// The worker holds `guard`, made by `admit`, only while it is let in.
+    pub(crate) async fn next_task<G>(
+        &self,
+        index: usize,
+        queue: &Arc<Mutex<VecDeque<DownloadTask>>>,
+        guard: &mut Option<G>,
+        admit: impl FnOnce() -> G,
+    ) -> Option<DownloadTask> {
+        let mut admit = Some(admit);
+        loop {
+            if index < self.active.load(Ordering::Relaxed) {
+                if let Some(admit) = admit.take().filter(|_| guard.is_none()) {
+                    *guard = Some(admit());
+                }
+                return queue.lock().unwrap().pop_front();
+            }
+            *guard = None;
+            if queue.lock().unwrap().is_empty() {
+                return None;
+            }
+            tokio::time::sleep(IDLE_POLL).await;
+        }
+    }
+}
+
+/// Where a download worker takes its tasks from.
+pub(crate) enum WorkerTasks {
+    /// A share of the queue dealt to this worker before the run.
+    Dealt(std::vec::IntoIter<DownloadTask>),
+    /// The whole queue, shared by workers numbered up to the scaling maximum.
+    Shared {
+        index: usize,
+        queue: Arc<Mutex<VecDeque<DownloadTask>>>,
+        scaling: Arc<WorkerScaling>,
+    },
+}
+
+impl WorkerTasks {
+    /// The next task of this worker, which holds `guard`, made by `admit`,
+    /// while it is taking tasks. A worker the scaling parks drops it until it
+    /// is let in again.
+    pub(crate) async fn next<G>(
+        &mut self,
+        guard: &mut Option<G>,
+        admit: impl FnOnce() -> G,
+    ) -> Option<DownloadTask> {
+        match self {
+            Self::Dealt(tasks) => {
+                guard.get_or_insert_with(admit);
+                tasks.next()
+            }
+            Self::Shared {
+                index,
+                queue,
+                scaling,
+            } => scaling.next_task(*index, queue, guard, admit).await,
+        }
+    }
+
+    /// Report how long the last task took to download.
+    pub(crate) fn record(&self, duration: Duration) {
+        if let Self::Shared { scaling, .. } = self {
+            scaling.record(duration);
+        }
+    }
+}
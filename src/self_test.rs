@@ -0,0 +1,123 @@
+use crate::manifest::{shard_url, Manifest, DEFAULT_MANIFEST_SHARD};
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{Client, Url};
+use serde::Serialize;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::net::{lookup_host, TcpStream};
+
+/// How long the connectivity check waits for each address.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The outcome of one check made by [`App::self_test`](crate::App::self_test).
+#[derive(Debug)]
+pub struct SelfTestStep {
+    pub name: &'static str,
+    /// What was found on success, what went wrong otherwise.
+    pub result: Result<String>,
+    /// What to look at when the check fails.
+    pub hint: &'static str,
+}
+
+impl SelfTestStep {
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Resolve the host and port of `domain`, defaulting the port from the scheme.
+pub(crate) async fn resolve(domain: &str) -> Result<Vec<SocketAddr>> {
+    let url = Url::parse(domain).context("The domain is not a valid URL")?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("The domain has no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("The domain has no port and an unknown scheme"))?;
+    let addresses: Vec<_> = lookup_host((host, port))
+        .await
+        .with_context(|| format!("Failed to resolve {}", host))?
+        .collect();
+    if addresses.is_empty() {
+        bail!("{} did not resolve to any address", host);
+    }
+    Ok(addresses)
+}
+
+/// Open a TCP connection to the first of `addresses` that accepts one within
+/// `timeout`.
+pub(crate) async fn connect(addresses: &[SocketAddr], timeout: Duration) -> Result<SocketAddr> {
+    let mut last_error = anyhow!("No addresses to connect to");
+    for &address in addresses {
+        match tokio::time::timeout(timeout, TcpStream::connect(address)).await {
+            Ok(Ok(_)) => return Ok(address),
+            Ok(Err(e)) => {
+                last_error = anyhow!(e).context(format!("Failed to connect to {}", address))
+            }
+            Err(_) => last_error = anyhow!("Timed out connecting to {}", address),
+        }
+    }
+    Err(last_error)
+}
+
+/// The outcome of [`health_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HealthStatus {
+    /// The manifest request was answered with a success status.
+    pub manifest_reachable: bool,
+    pub manifest_parse_ok: bool,
+    /// The first wallpaper of the manifest answered a `HEAD` request.
+    pub sample_wallpaper_reachable: bool,
+    /// How long the server took to answer the manifest request.
+    pub latency_ms: u64,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.manifest_reachable && self.manifest_parse_ok && self.sample_wallpaper_reachable
+    }
+}
+
+/// Check that the panels server at `domain` serves a manifest that parses
+/// and a wallpaper from it, before committing to a long download. Failed
+/// checks are reported in the [`HealthStatus`]; only a `domain` that is not
+/// a URL is an error. Does not need an [`App`](crate::App), so monitoring
+/// scripts can call it on its own.
+pub async fn health_check(domain: &str, client: &Client) -> Result<HealthStatus> {
+    Url::parse(domain).context("The domain is not a valid URL")?;
+    let mut status = HealthStatus {
+        manifest_reachable: false,
+        manifest_parse_ok: false,
+        sample_wallpaper_reachable: false,
+        latency_ms: 0,
+    };
+    let started = Instant::now();
+    let response = client
+        .get(shard_url(domain, DEFAULT_MANIFEST_SHARD))
+        .send()
+        .await;
+    status.latency_ms = started.elapsed().as_millis() as u64;
+    let response = match response {
+        Ok(response) if response.status().is_success() => response,
+        _ => return Ok(status),
+    };
+    status.manifest_reachable = true;
+    let Ok(manifest) = response.json::<Manifest>().await else {
+        return Ok(status);
+    };
+    status.manifest_parse_ok = true;
+    let sample = manifest
+        .wallpapers_sorted_by_key()
+        .into_iter()
+        .find_map(|(_, wallpaper)| wallpaper.wallpaper_url());
+    if let Some(url) = sample {
+        status.sample_wallpaper_reachable = client
+            .head(url)
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success());
+    }
+    Ok(status)
+}
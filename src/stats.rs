@@ -0,0 +1,85 @@
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+/// What an output directory holds, as found by
+/// [`App::directory_stats`](crate::App::directory_stats).
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DirectoryStats {
+    pub files: usize,
+    pub total_bytes: u64,
+    /// File count by lowercased extension, with `""` for files without one.
+    pub files_by_extension: BTreeMap<String, usize>,
+    pub largest_file: Option<PathBuf>,
+    pub largest_file_bytes: u64,
+    pub oldest_modified: Option<DateTime<Local>>,
+    pub newest_modified: Option<DateTime<Local>>,
+    /// Files a download of the current manifest would produce.
+    pub in_manifest: usize,
+    /// Files the current manifest no longer accounts for.
+    pub orphaned: usize,
+}
+
+impl DirectoryStats {
+    pub(crate) fn record_file(
+        &mut self,
+        path: PathBuf,
+        bytes: u64,
+        modified: Option<DateTime<Local>>,
+        in_manifest: bool,
+    ) {
+        self.files += 1;
+        self.total_bytes += bytes;
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        *self.files_by_extension.entry(extension).or_default() += 1;
+        if let Some(modified) = modified {
+            self.oldest_modified = Some(self.oldest_modified.map_or(modified, |t| t.min(modified)));
+            self.newest_modified = Some(self.newest_modified.map_or(modified, |t| t.max(modified)));
+        }
+        if in_manifest {
+            self.in_manifest += 1;
+        } else {
+            self.orphaned += 1;
+        }
+        if self.largest_file.is_none() || bytes > self.largest_file_bytes {
+            self.largest_file = Some(path);
+            self.largest_file_bytes = bytes;
+        }
+    }
+}
+
+/// One statistic per line, e.g. `Files: 396 (357.4 MB)`.
+impl fmt::Display for DirectoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = |bytes| humansize::format_size(bytes, humansize::DECIMAL);
+        writeln!(f, "Files: {} ({})", self.files, size(self.total_bytes))?;
+        for (extension, count) in &self.files_by_extension {
+            let extension = if extension.is_empty() {
+                "(none)"
+            } else {
+                extension
+            };
+            writeln!(f, "  {}: {}", extension, count)?;
+        }
+        if let Some(path) = &self.largest_file {
+            writeln!(
+                f,
+                "Largest: {} ({})",
+                path.display(),
+                size(self.largest_file_bytes)
+            )?;
+        }
+        if let (Some(oldest), Some(newest)) = (self.oldest_modified, self.newest_modified) {
+            writeln!(f, "Oldest: {}", oldest.format("%Y-%m-%d %H:%M:%S"))?;
+            writeln!(f, "Newest: {}", newest.format("%Y-%m-%d %H:%M:%S"))?;
+        }
+        write!(
+            f,
+            "In the manifest: {}, orphaned: {}",
+            self.in_manifest, self.orphaned
+        )
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RunState {
+    Running,
+    Complete,
+    Failed,
+}
+
+/// What the status file reports, updated by the workers as they go.
+#[derive(Debug, Clone, Serialize)]
+struct StatusSnapshot {
+    state: RunState,
+    downloaded: usize,
+    total: usize,
+    active_workers: usize,
+    bytes_per_second: f64,
+    /// Unknown until the first wallpaper is finished.
+    eta_seconds: Option<f64>,
+    #[serde(skip)]
+    bytes: u64,
+    /// Downloaded, skipped and failed wallpapers.
+    #[serde(skip)]
+    finished: usize,
+}
+
+/// A JSON file showing the progress of a run to monitoring tools, rewritten
+/// periodically and once more when the run ends.
+#[derive(Debug)]
+pub(crate) struct StatusFile {
+    path: PathBuf,
+    started: Instant,
+    snapshot: Mutex<StatusSnapshot>,
+}
+
+impl StatusFile {
+    pub(crate) fn new(path: PathBuf, total: usize) -> Self {
+        Self {
+            path,
+            started: Instant::now(),
+            snapshot: Mutex::new(StatusSnapshot {
+                state: RunState::Running,
+                downloaded: 0,
+                total,
+                active_workers: 0,
+                bytes_per_second: 0.0,
+                eta_seconds: None,
+                bytes: 0,
+                finished: 0,
+            }),
+        }
+    }
+
+    /// Count a worker as active until the returned guard is dropped.
+    pub(crate) fn worker_started(self: &Arc<Self>) -> StatusWorker {
+        self.snapshot.lock().unwrap().active_workers += 1;
+        StatusWorker(Arc::clone(self))
+    }
+
+    pub(crate) fn record_download(&self, bytes: u64) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.downloaded += 1;
+        snapshot.bytes += bytes;
+        snapshot.finished += 1;
+    }
+
+    /// Count a wallpaper that was skipped or failed.
+    pub(crate) fn record_not_downloaded(&self) {
+        self.snapshot.lock().unwrap().finished += 1;
+    }
+
+    /// Rewrite the file every `interval` until the returned task is aborted.
+    pub(crate) fn spawn_updates(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let status = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = status.write().await {
+                    warn!("{:#}", e);
+                }
+            }
+        })
+    }
+
+    /// Write the final state of the run.
+    pub(crate) async fn finish(&self, succeeded: bool) -> Result<()> {
+        self.snapshot.lock().unwrap().state = if succeeded {
+            RunState::Complete
+        } else {
+            RunState::Failed
+        };
+        self.write().await
+    }
+
+    /// Replace the file with the current snapshot. The JSON goes to a `.tmp`
+    /// file first and is renamed over the old one, so readers never see a
+    /// half-written file.
+    async fn write(&self) -> Result<()> {
+        let json = {
+            let mut snapshot = self.snapshot.lock().unwrap();
+            let elapsed = self.started.elapsed().as_secs_f64();
+            if elapsed > 0.0 {
+                snapshot.bytes_per_second = snapshot.bytes as f64 / elapsed;
+            }
+            snapshot.eta_seconds = (snapshot.finished > 0).then(|| {
+                let remaining = snapshot.total.saturating_sub(snapshot.finished);
+                elapsed / snapshot.finished as f64 * remaining as f64
+            });
+            serde_json::to_vec(&*snapshot).context("Failed to serialize the status")?
+        };
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        tokio::fs::write(&temporary, json)
+            .await
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .with_context(|| format!("Failed to replace {}", self.path.display()))
+    }
+}
+
+pub(crate) struct StatusWorker(Arc<StatusFile>);
+
+impl Drop for StatusWorker {
+    fn drop(&mut self) {
+        self.0.snapshot.lock().unwrap().active_workers -= 1;
+    }
+}
@@ -0,0 +1,56 @@
+use serde::{Serialize, Serializer};
+use std::{fmt, time::Duration};
+
+/// The outcome of a download run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub total_bytes: u64,
+    /// Serialized as fractional seconds.
+    #[serde(serialize_with = "serialize_secs")]
+    pub elapsed: Duration,
+    /// `(key, error)` for every wallpaper that failed to download.
+    pub errors: Vec<(String, String)>,
+}
+
+impl Summary {
+    pub(crate) fn record_download(&mut self, bytes: u64) {
+        self.downloaded += 1;
+        self.total_bytes += bytes;
+    }
+
+    pub(crate) fn record_skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    pub(crate) fn record_failure(&mut self, key: &str, error: &anyhow::Error) {
+        self.failed += 1;
+        self.errors.push((key.to_string(), format!("{:#}", error)));
+    }
+}
+
+/// A one-line report such as `Downloaded 215 wallpapers (1.2 GB), skipped 3,
+/// failed 0 in 42.1s`.
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Downloaded {} wallpapers ({}), skipped {}, failed {} in {:.1}s",
+            self.downloaded,
+            humansize::format_size(self.total_bytes, humansize::DECIMAL),
+            self.skipped,
+            self.failed,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// Serialize a duration as fractional seconds.
+pub(crate) fn serialize_secs<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
@@ -0,0 +1,518 @@
+use crate::dedupe::ContentIndex;
+use crate::error::PanelsError;
+use crate::etag_cache::EtagCache;
+use crate::rate_limit::{HostPermits, TokenBucket};
+use crate::retry::RetryPolicy;
+use crate::writer::FileWriter;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Datelike, NaiveDate};
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    Client, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cmp::max;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+/// How many bytes from the start of a download are enough to recognise its
+/// image format.
+const SNIFF_LEN: usize = 16;
+
+/// Which URLs of a manifest entry get turned into [`DownloadTask`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum DownloadStrategy {
+    /// Only the wallpaper itself, preferring `dhd` over `dsd`.
+    #[default]
+    HdOnly,
+    /// One file per entry, preferring the first compressed `wcs*` web
+    /// variant and falling back to the original.
+    WebOnly,
+    /// Every URL the entry has.
+    AllVariants,
+    /// Only the compressed `w*` variants served to the web app.
+    WebVariantsOnly,
+}
+
+/// How the file planned for a URL is named. The extension always comes from
+/// the image format the URL is expected to return.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub enum NamingStrategy {
+    /// The manifest key, plus the variant name when several are downloaded.
+    #[default]
+    ManifestKey,
+    /// The file stem of the URL path, e.g. `sunset_mountains_4k`.
+    UrlBasename,
+    /// A template in which `{key}` and `{basename}` are replaced by the
+    /// manifest key and the URL file stem.
+    Template(String),
+}
+
+impl NamingStrategy {
+    /// Rename `task` according to this strategy, keeping its extension.
+    pub(crate) fn apply(&self, task: &mut DownloadTask) {
+        let stem = match self {
+            Self::ManifestKey => return,
+            Self::UrlBasename => url_basename(&task.url).unwrap_or_else(|| task.key.clone()),
+            Self::Template(template) => template
+                .replace("{key}", &task.key)
+                .replace("{basename}", &url_basename(&task.url).unwrap_or_default()),
+        };
+        let filename = match Path::new(&task.filename).extension() {
+            Some(extension) => format!("{}.{}", stem, extension.to_string_lossy()),
+            None => stem,
+        };
+        task.filename = filename;
+    }
+}
+
+/// A path under the output directory for each file, in which `{year}`,
+/// `{month}`, `{day}`, `{key}` and `{ext}` are replaced, e.g.
+/// `{year}/{month}/{key}.{ext}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutputTemplate(String);
+
+impl OutputTemplate {
+    pub fn new(template: &str) -> Self {
+        Self(template.to_string())
+    }
+
+    /// What is wrong with the template, if anything: it must name each file
+    /// after its key and stay inside the output directory.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.0.contains("{key}") {
+            problems.push(String::from("the output template must contain {key}"));
+        }
+        let path = Path::new(&self.0);
+        if path.has_root() || path.components().any(|c| c == Component::ParentDir) {
+            problems.push(String::from(
+                "the output template must be a relative path without `..`",
+            ));
+        }
+        problems
+    }
+
+    /// The path, relative to the output directory, of the file for `key`.
+    /// Components that would leave the directory, e.g. from a key of `..`,
+    /// are replaced by `_`.
+    pub fn render(&self, key: &str, date: &NaiveDate, ext: &str) -> PathBuf {
+        let rendered = self
+            .0
+            .replace("{year}", &format!("{:04}", date.year()))
+            .replace("{month}", &format!("{:02}", date.month()))
+            .replace("{day}", &format!("{:02}", date.day()))
+            .replace("{key}", key)
+            .replace("{ext}", ext);
+        rendered
+            .split(['/', '\\'])
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment {
+                "." | ".." => "_",
+                segment => segment,
+            })
+            .collect()
+    }
+}
+
+/// Two levels of directories named after the first four hex digits of the
+/// SHA-256 of `key`, e.g. `ab/cd`, like Git's object store. Keeps any one
+/// directory small on filesystems that slow down with many entries.
+pub(crate) fn hash_directory(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    format!("{:02x}/{:02x}", digest[0], digest[1])
+}
+
+/// The file stem of the last segment of `url`'s path, if it has one.
+fn url_basename(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let segment = url.path_segments()?.next_back()?;
+    let stem = Path::new(segment).file_stem()?.to_string_lossy();
+    (!stem.is_empty()).then(|| stem.into_owned())
+}
+
+/// The settings every download of a run shares.
+#[derive(Clone)]
+pub(crate) struct Downloader {
+    pub(crate) client: Client,
+    pub(crate) download_dir: PathBuf,
+    pub(crate) retry_policy: Arc<RetryPolicy>,
+    pub(crate) etag_cache: Option<EtagCache>,
+    /// Moves file writes onto the blocking thread pool, bounded by its permits.
+    pub(crate) write_pool: Option<Arc<Semaphore>>,
+    /// Hard links files whose content was already downloaded under another key.
+    pub(crate) content_index: Option<Arc<ContentIndex>>,
+    pub(crate) timeout: Option<AdaptiveTimeout>,
+    pub(crate) host_permits: Option<Arc<HostPermits>>,
+    /// Stops downloads in flight, e.g. once the run's total timeout is up.
+    pub(crate) cancel: CancellationToken,
+    /// Wait for the last write to land, see [`AppBuilder::flush_writes`](crate::AppBuilder::flush_writes).
+    pub(crate) flush: bool,
+}
+
+/// A time limit for reading a download's body that grows with its size.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AdaptiveTimeout {
+    pub(crate) per_mb: Duration,
+    pub(crate) min: Duration,
+}
+
+impl AdaptiveTimeout {
+    /// How long a file of `bytes` may take, and never less than the minimum.
+    pub(crate) fn for_size(&self, bytes: u64) -> Duration {
+        max(self.min, self.per_mb.mul_f64(bytes as f64 / 1_000_000.0))
+    }
+}
+
+/// A single file to fetch, planned ahead of any network I/O.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DownloadTask {
+    /// The manifest key the URL was taken from.
+    pub key: String,
+    pub url: String,
+    pub filename: String,
+    /// The type the URL names with its `fm` parameter or extension. Without
+    /// one, the extension of `filename` is only a guess that the download
+    /// corrects, see [`DownloadTask::download`].
+    pub expected_content_type: Option<&'static str>,
+}
+
+impl DownloadTask {
+    /// Ask the server for the size of the file without downloading it.
+    pub(crate) async fn content_length(
+        &self,
+        client: &Client,
+        retry_policy: &RetryPolicy,
+    ) -> Result<Option<u64>> {
+        let response = retry_policy
+            .send(client.head(&self.url))
+            .await
+            .context("Failed to connect to server to size wallpaper")?
+            .error_for_status()
+            .context("The server refused the size request")?;
+        Ok(response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse().ok()))
+    }
+
+    /// Send `request` for the file according to the downloader's retry
+    /// policy, once its host has a free permit. The permit is handed back
+    /// with the response and should be held until the body has been read.
+    /// The status of the response is left to the caller.
+    pub(crate) async fn send(
+        &self,
+        downloader: &Downloader,
+        request: RequestBuilder,
+    ) -> Result<(Response, Option<OwnedSemaphorePermit>)> {
+        let permit = match (&downloader.host_permits, Url::parse(&self.url)) {
+            (Some(permits), Ok(url)) => {
+                Some(permits.acquire(url.host_str().unwrap_or_default()).await)
+            }
+            _ => None,
+        };
+        let response = tokio::select! {
+            response = downloader.retry_policy.send(request) => response,
+            () = downloader.cancel.cancelled() => Err(anyhow!("Cancelled")),
+        }
+        .context("Failed to connect to server to download wallpaper")?;
+        Ok((response, permit))
+    }
+
+    /// Stream the file into the download directory under its planned name,
+    /// calling `on_progress` with the bytes received so far and the expected
+    /// total after every chunk. A `rate_limiter` is charged for every chunk.
+    /// If the body is cut off or does not match the announced
+    /// `Content-Length`, the file is removed and the download retried
+    /// according to the retry policy, failing with
+    /// [`PanelsError::IncompleteDownload`] once it runs out. The same happens
+    /// when the download's [`AdaptiveTimeout`] runs out before the body is
+    /// complete. A cancelled download is not retried.
+    ///
+    /// With an etag cache the request is made conditional on the `ETag`
+    /// stored by an earlier run, and `None` is returned when the server
+    /// answers `304 Not Modified`. When it sends a changed file instead, that
+    /// replaces the old one once it is complete, even if the old one was
+    /// saved with a corrected extension.
+    ///
+    /// When the URL does not name its format, the file's extension is taken
+    /// from the `Content-Type` of the response, or if that is missing or
+    /// generic such as `application/octet-stream`, from the magic number its
+    /// content starts with, and is `bin` if neither is an image format. The
+    /// returned path has the corrected extension.
+    pub(crate) async fn download(
+        &self,
+        downloader: &Downloader,
+        mut rate_limiter: Option<&mut TokenBucket>,
+        mut on_progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<Option<(PathBuf, u64)>> {
+        let mut attempt = 0;
+        loop {
+            let downloaded = self
+                .download_once(downloader, rate_limiter.as_deref_mut(), &mut on_progress)
+                .await;
+            let incomplete = matches!(
+                downloaded.as_ref().map_err(|e| e.downcast_ref()),
+                Err(Some(PanelsError::IncompleteDownload { .. }))
+            );
+            if !incomplete
+                || downloader.cancel.is_cancelled()
+                || !downloader
+                    .retry_policy
+                    .retry_incomplete(&self.url, attempt)
+                    .await
+            {
+                return downloaded;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// One attempt at [`download`](Self::download).
+    async fn download_once(
+        &self,
+        downloader: &Downloader,
+        mut rate_limiter: Option<&mut TokenBucket>,
+        mut on_progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<Option<(PathBuf, u64)>> {
+        let path = downloader.download_dir.join(&self.filename);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .context("Failed to make the wallpaper's directory")?;
+        }
+        let mut request = downloader.client.get(&self.url);
+        // The file an earlier run stored the etag for, which is replaced.
+        let mut replacing = None;
+        if let Some(cache) = &downloader.etag_cache {
+            if let Some(entry) = cache.load(&self.url).await {
+                let stored = match &entry.file {
+                    Some(file) => downloader.download_dir.join(file),
+                    None => path.clone(),
+                };
+                // A stored etag only helps while the file it describes is still there.
+                if stored.exists() {
+                    request = request.header(IF_NONE_MATCH, entry.etag);
+                    replacing = Some(stored);
+                }
+            }
+        }
+        // A changed file is written beside the old one and only renamed over
+        // it once complete, so a failed download keeps the old copy.
+        let target = if replacing.is_some() {
+            partial_path(&path)
+        } else {
+            path.clone()
+        };
+        // Held until the body has been read, since that keeps the connection busy.
+        let (response, _permit) = self.send(downloader, request).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let mut response = response
+            .error_for_status()
+            .context("The server refused the wallpaper request")?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(String::from);
+        let bytes_expected = response.content_length();
+        let mut file_handle =
+            FileWriter::create_new(&target, downloader.write_pool.clone()).await?;
+        let mut hasher = downloader.content_index.as_ref().map(|_| Sha256::new());
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let mut bytes_received = 0;
+        // Without a size there is nothing to scale the limit by.
+        let time_limit = downloader
+            .timeout
+            .zip(bytes_expected)
+            .map(|(timeout, bytes)| timeout.for_size(bytes));
+        let started = Instant::now();
+        loop {
+            let next_chunk = async {
+                match time_limit {
+                    Some(limit) => {
+                        match tokio::time::timeout_at(started + limit, response.chunk()).await {
+                            Ok(next) => next.context("Failed to recieve data from the server"),
+                            Err(_) => Err(anyhow!("Timed out after {:.1?}", limit)),
+                        }
+                    }
+                    None => response
+                        .chunk()
+                        .await
+                        .context("Failed to recieve data from the server"),
+                }
+            };
+            let next = tokio::select! {
+                next = next_chunk => next,
+                () = downloader.cancel.cancelled() => Err(anyhow!("Cancelled")),
+            };
+            let chunk = match next {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    drop(file_handle);
+                    remove_partial_file(&target).await?;
+                    return Err(e.context(PanelsError::IncompleteDownload {
+                        url: self.url.clone(),
+                        expected: bytes_expected,
+                        received: bytes_received,
+                    }));
+                }
+            };
+            let chunk_len = chunk.len() as u64;
+            let missing = SNIFF_LEN - head.len();
+            head.extend_from_slice(&chunk[..missing.min(chunk.len())]);
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&chunk);
+            }
+            if let Err(e) = file_handle.write_all(chunk).await {
+                drop(file_handle);
+                remove_partial_file(&target).await?;
+                return Err(e.context("Failed to write wallpaper data to file"));
+            }
+            if let Some(bucket) = rate_limiter.as_deref_mut() {
+                bucket.acquire(chunk_len).await;
+            }
+            bytes_received += chunk_len;
+            trace!("Received {} bytes of {}", bytes_received, self.url);
+            on_progress(bytes_received, bytes_expected);
+        }
+        if downloader.flush {
+            if let Err(e) = file_handle.flush().await {
+                drop(file_handle);
+                remove_partial_file(&target).await?;
+                return Err(e.context("Failed to flush file contents"));
+            }
+        }
+        if bytes_expected.is_some_and(|expected| expected != bytes_received) {
+            drop(file_handle);
+            remove_partial_file(&target).await?;
+            return Err(PanelsError::IncompleteDownload {
+                url: self.url.clone(),
+                expected: bytes_expected,
+                received: bytes_received,
+            }
+            .into());
+        }
+        let extension = match self.expected_content_type {
+            Some(_) => None,
+            None => Some(
+                content_type
+                    .as_deref()
+                    .and_then(content_type_extension)
+                    .or_else(|| sniff_extension(&head))
+                    .unwrap_or("bin"),
+            ),
+        };
+        let path = match (replacing, extension) {
+            (Some(stored), extension) => {
+                let path = match extension {
+                    Some(extension) => path.with_extension(extension),
+                    None => path,
+                };
+                tokio::fs::rename(&target, &path)
+                    .await
+                    .context("Failed to replace the changed wallpaper")?;
+                // A changed format leaves the old file under its old extension.
+                if stored != path {
+                    tokio::fs::remove_file(&stored)
+                        .await
+                        .context("Failed to remove the replaced wallpaper")?;
+                }
+                path
+            }
+            (None, Some(extension)) => correct_extension(path, extension).await?,
+            (None, None) => path,
+        };
+        if let (Some(index), Some(hasher)) = (&downloader.content_index, hasher) {
+            if let Some(original) = index
+                .link_duplicate(hasher.finalize().into(), &path)
+                .await?
+            {
+                debug!(
+                    "{} has the same content as {}, linked instead",
+                    path.display(),
+                    original.display()
+                );
+            }
+        }
+        if let (Some(cache), Some(etag)) = (&downloader.etag_cache, etag) {
+            let file = path.strip_prefix(&downloader.download_dir).unwrap_or(&path);
+            cache.store(&self.url, &etag, file).await?;
+        }
+        Ok(Some((path, bytes_received)))
+    }
+}
+
+/// The extension for an image `content_type`, ignoring parameters such as
+/// `charset`. Generic types like `application/octet-stream` have none.
+fn content_type_extension(content_type: &str) -> Option<&'static str> {
+    let essence = content_type.split(';').next()?.trim();
+    match essence.to_ascii_lowercase().as_str() {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/webp" => Some("webp"),
+        "image/avif" => Some("avif"),
+        "image/gif" => Some("gif"),
+        _ => None,
+    }
+}
+
+/// The extension for the image format whose magic number `head` starts with.
+fn sniff_extension(head: &[u8]) -> Option<&'static str> {
+    match head {
+        [0xFF, 0xD8, 0xFF, ..] => Some("jpg"),
+        [0x89, b'P', b'N', b'G', ..] => Some("png"),
+        [b'G', b'I', b'F', b'8', ..] => Some("gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("webp"),
+        // An ISO media `ftyp` box whose major brand is AVIF.
+        [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => Some("avif"),
+        _ => None,
+    }
+}
+
+/// Rename the file at `path` to have `extension`, refusing to replace an
+/// existing file just like the download itself does.
+async fn correct_extension(path: PathBuf, extension: &str) -> Result<PathBuf> {
+    let corrected = path.with_extension(extension);
+    if corrected == path {
+        return Ok(path);
+    }
+    if tokio::fs::try_exists(&corrected).await.unwrap_or(true) {
+        remove_partial_file(&path).await?;
+        bail!("{} already exists", corrected.display());
+    }
+    tokio::fs::rename(&path, &corrected)
+        .await
+        .context("Failed to give the wallpaper its extension")?;
+    debug!("Renamed {} to {}", path.display(), corrected.display());
+    Ok(corrected)
+}
+
+/// Where a download replacing the file at `path` is written first.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    partial.into()
+}
+
+/// Remove what was written of a download that did not complete.
+async fn remove_partial_file(path: &Path) -> Result<()> {
+    tokio::fs::remove_file(path)
+        .await
+        .context("Failed to remove incomplete wallpaper")
+}
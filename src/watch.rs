@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// How many manifest paths may wait to be processed before the watcher
+/// thread blocks.
+const PENDING_MANIFESTS: usize = 64;
+
+/// Watch `directory` for `.json` files being created, finished or moved into
+/// it, and send their paths over the returned channel. A file can be sent
+/// more than once, e.g. when it is created and then closed. The directory is
+/// watched for as long as the watcher lives.
+pub(crate) fn watch_manifests(
+    directory: &Path,
+) -> Result<(RecommendedWatcher, mpsc::Receiver<PathBuf>)> {
+    let (sender, receiver) = mpsc::channel(PENDING_MANIFESTS);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Failed to watch for manifests: {}", e);
+                return;
+            }
+        };
+        if !matches!(
+            event.kind,
+            EventKind::Create(_)
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                | EventKind::Access(AccessKind::Close(AccessMode::Write))
+        ) {
+            return;
+        }
+        // A file copied in is created empty and reported again once written.
+        let created = matches!(event.kind, EventKind::Create(_));
+        for path in event.paths {
+            let empty = std::fs::metadata(&path).is_ok_and(|metadata| metadata.len() == 0);
+            if path.extension() == Some("json".as_ref()) && !(created && empty) {
+                // Only fails once the receiver is gone, i.e. nobody is watching.
+                let _ = sender.blocking_send(path);
+            }
+        }
+    })
+    .context("Failed to start watching for manifests")?;
+    watcher
+        .watch(directory, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", directory.display()))?;
+    Ok((watcher, receiver))
+}
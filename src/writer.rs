@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::{io::Write, path::Path, sync::Arc};
+use tokio::{io::AsyncWriteExt, sync::Semaphore};
+
+/// Where the bytes of a download are written. By default writes go through
+/// tokio's file handle on the worker task. With a pool, each write runs on
+/// the blocking thread pool, and at most as many writes as the pool has
+/// permits run at once across all workers.
+pub(crate) enum FileWriter {
+    Inline(tokio::fs::File),
+    Pooled {
+        // Taken while a write is running on the blocking pool.
+        file: Option<std::fs::File>,
+        pool: Arc<Semaphore>,
+    },
+}
+
+impl FileWriter {
+    /// Create `path`, failing if it already exists.
+    pub(crate) async fn create_new(path: &Path, pool: Option<Arc<Semaphore>>) -> Result<Self> {
+        let file = tokio::fs::File::create_new(path)
+            .await
+            .context("Failed to open filepath")?;
+        Ok(match pool {
+            None => Self::Inline(file),
+            Some(pool) => Self::Pooled {
+                file: Some(file.into_std().await),
+                pool,
+            },
+        })
+    }
+
+    pub(crate) async fn write_all(&mut self, chunk: Bytes) -> Result<()> {
+        match self {
+            Self::Inline(file) => file.write_all(&chunk).await?,
+            Self::Pooled { file, pool } => {
+                let _permit = pool.acquire().await?;
+                let mut handle = file.take().context("A previous write did not finish")?;
+                let (handle, written) = tokio::task::spawn_blocking(move || {
+                    let written = handle.write_all(&chunk);
+                    (handle, written)
+                })
+                .await
+                .context("File write panicked")?;
+                *file = Some(handle);
+                written?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        match self {
+            Self::Inline(file) => file.flush().await?,
+            Self::Pooled { file, .. } => {
+                if let Some(file) = file {
+                    file.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
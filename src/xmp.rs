@@ -0,0 +1,133 @@
+use crate::task::DownloadTask;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Local;
+use serde::Serialize;
+use std::path::Path;
+use tracing::debug;
+
+/// The namespace of the `panels:` properties.
+const PANELS_NAMESPACE: &str = "https://github.com/Jwenzel1/panels-downloader/ns/1.0/";
+/// What an APP1 segment holding XMP starts with.
+const XMP_SEGMENT_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// The value replaced by what the download itself knows for `key`,
+/// `source_url` and `download_date`.
+const AUTO: &str = "auto";
+
+/// A `panels:` property written into the XMP metadata of downloaded JPEGs,
+/// parsed from `name=value`, with or without the `panels:` prefix. A value
+/// of `auto` for `key`, `source_url` or `download_date` is replaced by the
+/// manifest key, the URL and the date of the download.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct XmpTag {
+    name: String,
+    value: String,
+}
+
+impl XmpTag {
+    /// Parse `name=value`, e.g. `panels:key=auto` or `collection=autumn`.
+    pub fn parse(tag: &str) -> Result<Self> {
+        let (name, value) = tag
+            .split_once('=')
+            .ok_or_else(|| anyhow!("`{}` should be name=value", tag))?;
+        let name = name.strip_prefix("panels:").unwrap_or(name);
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid {
+            bail!("`{}` is not a valid XMP property name", name);
+        }
+        Ok(Self {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    /// The value written for `task`, with `auto` filled in.
+    fn value_for(&self, task: &DownloadTask) -> String {
+        match (self.name.as_str(), self.value.as_str()) {
+            ("key", AUTO) => task.key.clone(),
+            ("source_url", AUTO) => task.url.clone(),
+            ("download_date", AUTO) => Local::now().format("%Y-%m-%d").to_string(),
+            _ => self.value.clone(),
+        }
+    }
+}
+
+/// Add `tags` to the XMP metadata of the wallpaper downloaded for `task` to
+/// `path`. Files that are not JPEGs are left alone. The file is rewritten
+/// and renamed into place, which also unlinks it from any deduplicated copy.
+pub(crate) async fn embed(path: &Path, task: &DownloadTask, tags: &[XmpTag]) -> Result<()> {
+    let jpeg = tokio::fs::read(path)
+        .await
+        .context("Failed to read the wallpaper to tag")?;
+    if !jpeg.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        debug!("{} is not a JPEG, not tagging it", path.display());
+        return Ok(());
+    }
+    let tagged = with_xmp_segment(&jpeg, &xmp_packet(task, tags))?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    tokio::fs::write(&partial, tagged)
+        .await
+        .context("Failed to write the tagged wallpaper")?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .context("Failed to replace the wallpaper with its tagged copy")
+}
+
+fn xmp_packet(task: &DownloadTask, tags: &[XmpTag]) -> String {
+    let properties: String = tags
+        .iter()
+        .map(|tag| {
+            format!(
+                "<panels:{0}>{1}</panels:{0}>",
+                tag.name,
+                escape_xml(&tag.value_for(task))
+            )
+        })
+        .collect();
+    format!(
+        concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+            "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+            "<rdf:Description rdf:about=\"\" xmlns:panels=\"{}\">{}</rdf:Description>",
+            "</rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>"
+        ),
+        PANELS_NAMESPACE, properties
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `jpeg` with an APP1 segment holding `packet` after its SOI marker and any
+/// APP0 (JFIF) segments, which have to come first.
+fn with_xmp_segment(jpeg: &[u8], packet: &str) -> Result<Vec<u8>> {
+    let payload_len = XMP_SEGMENT_HEADER.len() + packet.len();
+    // The length counts its own two bytes.
+    let segment_len = u16::try_from(payload_len + 2)
+        .map_err(|_| anyhow!("The XMP metadata does not fit in a JPEG segment"))?;
+    let mut at = 2;
+    while jpeg.get(at..at + 2) == Some(&[0xFF, 0xE0]) {
+        let Some(&[high, low]) = jpeg.get(at + 2..at + 4) else {
+            break;
+        };
+        at += 2 + usize::from(u16::from_be_bytes([high, low]));
+    }
+    let at = at.min(jpeg.len());
+    let mut tagged = Vec::with_capacity(jpeg.len() + payload_len + 4);
+    tagged.extend_from_slice(&jpeg[..at]);
+    tagged.extend_from_slice(&[0xFF, 0xE1]);
+    tagged.extend_from_slice(&segment_len.to_be_bytes());
+    tagged.extend_from_slice(XMP_SEGMENT_HEADER);
+    tagged.extend_from_slice(packet.as_bytes());
+    tagged.extend_from_slice(&jpeg[at..]);
+    Ok(tagged)
+}
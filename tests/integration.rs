@@ -0,0 +1,1948 @@
+use flate2::{write::GzEncoder, Compression};
+use futures_util::TryStreamExt;
+use panels_downloader::{
+    App, AppBuilder, CircuitBreaker, CircuitState, DownloadContinuation, ErrorStrategy,
+    ExtraHeaders, FieldMap, Manifest, ManifestCache, ManifestData, MergeStrategy, NamingStrategy,
+    OutputTemplate, PanelsError, PidFile, ProgressEvent, TokenBucket, IGNORE_FILE_NAME,
+};
+use proptest::prelude::*;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use wiremock::matchers::{header, method, path, path_regex};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const MANIFEST_PATH: &str = "/panels-api/data/20240916/media-1a-i-p~s";
+const WALLPAPER: &[u8] = b"not really a jpeg";
+
+fn manifest_json(server: &MockServer, keys: &[&str]) -> serde_json::Value {
+    let data: serde_json::Map<_, _> = keys
+        .iter()
+        .map(|key| {
+            let url = format!("{}/content/{}.jpg?fm=jpg", server.uri(), key);
+            (key.to_string(), json!({ "dhd": url }))
+        })
+        .collect();
+    json!({ "version": 1, "data": data })
+}
+
+async fn mount_wallpapers(server: &MockServer) {
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn gzip_manifest_is_decompressed() {
+    let server = MockServer::start().await;
+    let manifest = manifest_json(&server, &["first", "second"]);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(manifest.to_string().as_bytes()).unwrap();
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .and(header("accept-encoding", "gzip"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("content-encoding", "gzip")
+                .insert_header("content-type", "application/json")
+                .set_body_bytes(encoder.finish().unwrap()),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .gzip_manifest(true)
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read_dir(output.path()).unwrap().count(), 2);
+}
+
+#[tokio::test]
+async fn every_wallpaper_is_downloaded_once_under_its_key() {
+    let server = MockServer::start().await;
+    let keys = ["a", "b", "c", "d"];
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &keys)))
+        .mount(&server)
+        .await;
+    // Every key has its own body so each file shows which wallpaper it holds,
+    // and its own mock so each is served exactly once.
+    for key in keys {
+        Mock::given(method("GET"))
+            .and(path(format!("/content/{}.jpg", key)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(key))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let output = tempfile::tempdir().unwrap();
+    App::new(&server.uri(), output.path().to_str().unwrap(), 2)
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read_dir(output.path()).unwrap().count(), 4);
+    for key in keys {
+        let path = output.path().join(format!("{}.jpg", key));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), key);
+    }
+}
+
+#[tokio::test]
+async fn wallpapers_are_dealt_round_robin_to_workers() {
+    let server = MockServer::start().await;
+    let keys = ["a", "b", "c", "d"];
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &keys)))
+        .mount(&server)
+        .await;
+    // The first worker is held up on `a`, so the second only finishes its
+    // share early if it was dealt `b` and `d` rather than taking `c` too.
+    Mock::given(method("GET"))
+        .and(path("/content/a.jpg"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(WALLPAPER)
+                .set_delay(Duration::from_millis(300)),
+        )
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let output = tempfile::tempdir().unwrap();
+    let mut app = App::new(&server.uri(), output.path().to_str().unwrap(), 2);
+    let recorded = Arc::clone(&events);
+    app.set_progress_callback(move |event| {
+        let record = match event {
+            ProgressEvent::DownloadStarted { key } => format!("started {}", key),
+            ProgressEvent::DownloadComplete { key, .. } => format!("downloaded {}", key),
+            _ => return,
+        };
+        recorded.lock().unwrap().push(record);
+    });
+
+    app.run().await.unwrap();
+
+    let events = events.lock().unwrap();
+    let at = |record: &str| events.iter().position(|r| r == record).unwrap();
+    assert!(at("downloaded b") < at("started d"));
+    assert!(at("downloaded d") < at("downloaded a"));
+    assert!(at("downloaded a") < at("started c"));
+}
+
+#[tokio::test]
+async fn a_url_under_several_keys_is_downloaded_once() {
+    let server = MockServer::start().await;
+    let url = format!("{}/content/shared.jpg", server.uri());
+    let manifest = json!({
+        "version": 1,
+        "data": { "first": { "dhd": url }, "second": { "dhd": url } },
+    });
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/shared.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let summary = App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+    assert!(output.path().join("first.jpg").exists());
+}
+
+#[tokio::test]
+async fn a_shared_url_is_still_downloaded_when_its_first_key_is_ignored() {
+    let server = MockServer::start().await;
+    let url = format!("{}/content/shared.jpg", server.uri());
+    let manifest = json!({
+        "version": 1,
+        "data": { "first": { "dhd": url }, "second": { "dhd": url } },
+    });
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let output = tempfile::tempdir().unwrap();
+    std::fs::write(output.path().join(IGNORE_FILE_NAME), "first\n").unwrap();
+
+    let summary = App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+    assert!(output.path().join("second.jpg").exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinks_are_named_after_the_key_and_never_replace_files() {
+    let server = MockServer::start().await;
+    let url = format!("{}/content/sunset.jpg?fm=jpg", server.uri());
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "version": 1,
+            "data": { "first": { "dhd": url } },
+        })))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let output = tempfile::tempdir().unwrap();
+    let links = tempfile::tempdir().unwrap();
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .naming_strategy(NamingStrategy::UrlBasename)
+        .symlinks_directory(Some(links.path().to_path_buf()))
+        .build();
+
+    app.run().await.unwrap();
+    let link = links.path().join("first.jpg");
+    assert!(std::fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(std::fs::read(&link).unwrap(), WALLPAPER);
+
+    // With the links inside the output directory, a download is never removed
+    // to make room for one.
+    let output = tempfile::tempdir().unwrap();
+    std::fs::write(output.path().join("first.jpg"), "a real file").unwrap();
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .naming_strategy(NamingStrategy::UrlBasename)
+        .symlinks_directory(Some(output.path().to_path_buf()))
+        .build();
+
+    assert!(app.run().await.is_err());
+    let kept = std::fs::read_to_string(output.path().join("first.jpg")).unwrap();
+    assert_eq!(kept, "a real file");
+}
+
+#[tokio::test]
+async fn retryable_statuses_are_retried() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["flaky"])))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/flaky.jpg"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run()
+        .await
+        .unwrap();
+
+    let wallpaper = std::fs::read(output.path().join("flaky.jpg")).unwrap();
+    assert_eq!(wallpaper, WALLPAPER);
+}
+
+#[tokio::test]
+async fn a_truncated_body_is_retried() {
+    // wiremock always sends whole bodies, so the wallpaper comes from a bare
+    // socket that cuts off its first answer after the headers and part of it.
+    let wallpapers = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/cut.jpg", wallpapers.local_addr().unwrap());
+    tokio::spawn(async move {
+        for cut_off in [true, false] {
+            let (mut socket, _) = wallpapers.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            let length = if cut_off { 1000 } else { WALLPAPER.len() };
+            let head = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", length);
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(WALLPAPER).await.unwrap();
+        }
+    });
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "version": 1,
+            "data": { "cut": { "dhd": url } },
+        })))
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let summary = App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+    assert_eq!(
+        std::fs::read(output.path().join("cut.jpg")).unwrap(),
+        WALLPAPER
+    );
+}
+
+#[tokio::test]
+async fn manifest_retries_are_separate_from_download_retries() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["only"])))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    let summary = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .max_retries(0)
+        .manifest_max_retries(2)
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+}
+
+#[tokio::test]
+async fn best_effort_keeps_going_after_a_failed_download() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["broken", "fine"])),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/broken.jpg"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    let summary = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .error_strategy(ErrorStrategy::BestEffort)
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!((summary.downloaded, summary.failed), (1, 1));
+    assert_eq!(summary.errors[0].0, "broken");
+}
+
+#[tokio::test]
+async fn fail_fast_returns_the_error_of_the_failed_download() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["broken"])))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/broken.jpg"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let error = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .error_strategy(ErrorStrategy::FailFast)
+        .build()
+        .run()
+        .await
+        .unwrap_err();
+
+    let message = format!("{:#}", error);
+    assert!(message.contains("404"), "{}", message);
+    assert!(!message.contains("download workers failed"), "{}", message);
+}
+
+#[tokio::test]
+async fn an_open_circuit_skips_downloads_until_one_succeeds() {
+    let server = MockServer::start().await;
+    let keys = ["a_fails", "b_slow", "c_skipped", "d_resumes"];
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &keys)))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/a_fails.jpg"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // Still in flight when the circuit opens, and closes it by succeeding.
+    Mock::given(method("GET"))
+        .and(path("/content/b_slow.jpg"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(WALLPAPER)
+                .set_delay(Duration::from_millis(300)),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/c_skipped.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(0)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/d_resumes.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // Two workers are dealt a_fails and c_skipped, and b_slow and d_resumes.
+    let output = tempfile::tempdir().unwrap();
+    let summary = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 2)
+        .error_strategy(ErrorStrategy::BestEffort)
+        .circuit_breaker_threshold(Some(1))
+        .circuit_breaker_reset_timeout(Duration::from_secs(60))
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        (summary.downloaded, summary.skipped, summary.failed),
+        (2, 1, 1)
+    );
+}
+
+#[test]
+fn the_circuit_breaker_lets_one_probe_through_after_the_cooldown() {
+    let cooldown = Duration::from_millis(50);
+    let mut breaker = CircuitBreaker::new(2, cooldown);
+
+    breaker.record_failure();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    assert!(breaker.allow_request());
+    breaker.record_failure();
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert!(!breaker.allow_request());
+
+    std::thread::sleep(cooldown);
+    assert!(breaker.allow_request());
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    assert!(!breaker.allow_request());
+    // A failed probe opens the circuit for another cooldown.
+    breaker.record_failure();
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert!(!breaker.allow_request());
+
+    std::thread::sleep(cooldown);
+    assert!(breaker.allow_request());
+    breaker.record_success();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    assert!(breaker.allow_request());
+}
+
+#[tokio::test]
+async fn error_pages_are_not_downloaded_to_memory() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["broken", "fine"])),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/broken.jpg"))
+        .respond_with(ResponseTemplate::new(404).set_body_string("Not Found"))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let wallpapers = AppBuilder::new(&server.uri(), "unused", 1)
+        .error_strategy(ErrorStrategy::BestEffort)
+        .build()
+        .download_to_memory(1024)
+        .await
+        .unwrap();
+
+    assert_eq!(wallpapers.keys().collect::<Vec<_>>(), ["fine"]);
+    assert_eq!(wallpapers["fine"], WALLPAPER);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn best_effort_counts_a_failed_symlink_as_a_failed_download() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["blocked", "fine"])),
+        )
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let output = tempfile::tempdir().unwrap();
+    let links = tempfile::tempdir().unwrap();
+    std::fs::write(links.path().join("blocked.jpg"), "a real file").unwrap();
+
+    let summary = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .symlinks_directory(Some(links.path().to_path_buf()))
+        .error_strategy(ErrorStrategy::BestEffort)
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!((summary.downloaded, summary.failed), (1, 1));
+    assert_eq!(summary.errors[0].0, "blocked");
+}
+
+#[tokio::test]
+async fn retries_stop_once_the_host_budget_is_used_up() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["down"])))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .respond_with(ResponseTemplate::new(503))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let result = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .max_retries(3)
+        .max_retries_per_host(Some(1))
+        .build()
+        .run()
+        .await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn wallpapers_disallowed_by_robots_txt_are_not_downloaded() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/robots.txt"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_string("User-agent: *\nDisallow: /content/\n"),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["alpha"])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let error = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .respect_robots_txt(true)
+        .build()
+        .run()
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(PanelsError::DisallowedByRobots(_))
+    ));
+}
+
+#[tokio::test]
+async fn downloads_are_cancelled_at_the_total_timeout() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["slow"])))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(WALLPAPER)
+                .set_delay(Duration::from_secs(30)),
+        )
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let error = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .total_timeout(Some(Duration::from_millis(200)))
+        .build()
+        .run()
+        .await
+        .unwrap_err();
+
+    match error.downcast_ref() {
+        Some(PanelsError::TotalTimeoutExceeded { summary, .. }) => {
+            assert_eq!((summary.downloaded, summary.failed), (0, 0));
+        }
+        other => panic!("expected a total timeout, got {:?}", other),
+    }
+    assert!(!output.path().join("slow.jpg").exists());
+}
+
+#[tokio::test]
+async fn a_paused_run_continues_without_fetching_the_manifest_again() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(manifest_json(&server, &["alpha", "beta", "gamma"])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let output = tempfile::tempdir().unwrap();
+    let app = App::new(&server.uri(), output.path().to_str().unwrap(), 1);
+
+    let (summary, continuation) = app.run_n_then_pause(1).await.unwrap();
+    assert_eq!((summary.downloaded, continuation.len()), (1, 2));
+    let saved = output.path().join("continuation.json");
+    continuation.save(&saved).unwrap();
+    let summary = app
+        .continue_from(DownloadContinuation::load(&saved).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 2);
+    for key in ["alpha", "beta", "gamma"] {
+        assert!(output.path().join(format!("{}.jpg", key)).exists());
+    }
+}
+
+#[tokio::test]
+async fn metrics_are_served_once_and_count_every_run() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["alpha", "beta"])),
+        )
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let output = tempfile::tempdir().unwrap();
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .metrics_port(Some(port))
+        .build();
+
+    let (_, continuation) = app.run_n_then_pause(1).await.unwrap();
+    app.continue_from(continuation).await.unwrap();
+
+    let metrics = reqwest::get(format!("http://127.0.0.1:{}/metrics", port))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("panels_downloads_total{status=\"ok\"} 2"));
+}
+
+#[tokio::test]
+async fn delete_missing_keeps_sniffed_extensions_and_cleans_subdirectories() {
+    let server = MockServer::start().await;
+    let manifest: Manifest = serde_json::from_value(manifest_json(&server, &["kept"])).unwrap();
+    let output = tempfile::tempdir().unwrap();
+    std::fs::create_dir(output.path().join("nested")).unwrap();
+    // `kept.png` stands for a download whose extension was sniffed.
+    for name in ["nested/kept.png", "nested/gone.jpg", "gone.jpg", ".hidden"] {
+        std::fs::write(output.path().join(name), WALLPAPER).unwrap();
+    }
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .output_template(Some(OutputTemplate::new("nested/{key}.{ext}")))
+        .build();
+
+    let orphans = app.delete_missing(&manifest, true).await.unwrap();
+
+    let gone = [
+        output.path().join("gone.jpg"),
+        output.path().join("nested/gone.jpg"),
+    ];
+    assert_eq!(orphans, gone);
+    assert!(gone.iter().all(|path| !path.exists()));
+    assert!(output.path().join("nested/kept.png").exists());
+    assert!(output.path().join(".hidden").exists());
+}
+
+#[tokio::test]
+async fn a_dry_run_lists_what_delete_missing_would_delete() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["kept"])))
+        .mount(&server)
+        .await;
+    let output = tempfile::tempdir().unwrap();
+    for name in ["kept.jpg", "gone.jpg"] {
+        std::fs::write(output.path().join(name), WALLPAPER).unwrap();
+    }
+    let manifest = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(
+        manifest.path(),
+        manifest_json(&server, &["kept"]).to_string(),
+    )
+    .unwrap();
+
+    let dry_run = |output_format: &'static str| {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_panels-downloader"))
+            .args(["--insecure-http", "--dry-run", "--confirm-delete"])
+            .args(["--domain", &server.uri()])
+            .arg("--output-dir")
+            .arg(output.path())
+            .arg("--delete-missing")
+            .arg(manifest.path())
+            .args(["--output-format", output_format])
+            .output()
+    };
+    let text = dry_run("text").await.unwrap();
+    let ndjson = dry_run("ndjson").await.unwrap();
+
+    assert!(text.status.success() && ndjson.status.success());
+    let gone = output.path().join("gone.jpg");
+    let text = String::from_utf8(text.stdout).unwrap();
+    assert!(
+        text.contains(&format!("Would delete {}\n", gone.display())),
+        "{}",
+        text
+    );
+    let ndjson = String::from_utf8(ndjson.stdout).unwrap();
+    let record: serde_json::Value = ndjson
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .find(|record: &serde_json::Value| record["event"] == "orphan")
+        .unwrap();
+    assert_eq!(
+        record,
+        json!({ "event": "orphan", "path": gone, "deleted": false })
+    );
+    assert!(gone.exists());
+}
+
+#[tokio::test]
+async fn run_dry_plans_only_the_filtered_keys() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(
+            &server,
+            &["sunset-1", "sunset-2", "forest-1"],
+        )))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let tasks = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .key_prefix(Some(String::from("sunset-")))
+        .build()
+        .run_dry()
+        .await
+        .unwrap();
+
+    let filenames: Vec<_> = tasks.iter().map(|task| task.filename.as_str()).collect();
+    assert_eq!(filenames, ["sunset-1.jpg", "sunset-2.jpg"]);
+    assert_eq!(std::fs::read_dir(output.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn keys_in_the_ignore_file_are_not_downloaded() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["kept", "ignored"])),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/ignored.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(0)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    std::fs::write(
+        output.path().join(IGNORE_FILE_NAME),
+        "# Not part of the collection\nignored # too dark\n",
+    )
+    .unwrap();
+    let summary = App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+    assert!(output.path().join("kept.jpg").exists());
+    assert!(!output.path().join("ignored.jpg").exists());
+}
+
+#[tokio::test]
+async fn only_keys_missing_from_the_checkpoint_are_downloaded() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["old", "new"])),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/old.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(0)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    let checkpoint = output.path().join("checkpoint.txt");
+    std::fs::write(&checkpoint, "old\n").unwrap();
+    let summary = AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .checkpoint(Some(checkpoint.clone()))
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+    assert_eq!(std::fs::read_to_string(checkpoint).unwrap(), "old\nnew\n");
+}
+
+#[tokio::test]
+async fn the_newest_dated_manifest_path_is_found() {
+    let server = MockServer::start().await;
+    let yesterday = chrono::Local::now().date_naive() - chrono::Days::new(1);
+    let newest = format!(
+        "/panels-api/data/{}/media-1a-i-p~s",
+        yesterday.format("%Y%m%d")
+    );
+    for manifest_path in [newest.as_str(), MANIFEST_PATH] {
+        Mock::given(method("GET"))
+            .and(path(manifest_path))
+            .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &[])))
+            .mount(&server)
+            .await;
+    }
+
+    let output = tempfile::tempdir().unwrap();
+    let found = App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .latest_manifest_path()
+        .await
+        .unwrap();
+
+    assert_eq!(found, Some(newest));
+}
+
+#[tokio::test]
+async fn the_found_manifest_path_is_fetched_with_manifest_url() {
+    let server = MockServer::start().await;
+    let newest = "/panels-api/data/20991231/media-1a-i-p~s";
+    Mock::given(method("GET"))
+        .and(path(newest))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["new"])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["old"])))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let output = tempfile::tempdir().unwrap();
+    let manifest = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .manifest_url(Some(newest.to_string()))
+        .build()
+        .fetch_manifest()
+        .await
+        .unwrap();
+
+    assert!(manifest.data.contains_key("new"));
+}
+
+#[tokio::test]
+async fn a_changed_wallpaper_replaces_the_cached_one() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["changed"])),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/changed.jpg"))
+        .and(header("if-none-match", "\"v1\""))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"v2\"")
+                .set_body_bytes(b"the new wallpaper".as_slice()),
+        )
+        .expect(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/changed.jpg"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"v1\"")
+                .set_body_bytes(WALLPAPER),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let output = tempfile::tempdir().unwrap();
+    let etag_cache = tempfile::tempdir().unwrap();
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .etag_cache_dir(Some(etag_cache.path().to_path_buf()))
+        .build();
+
+    app.run().await.unwrap();
+    let summary = app.run().await.unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+    let wallpaper = std::fs::read(output.path().join("changed.jpg")).unwrap();
+    assert_eq!(wallpaper, b"the new wallpaper");
+    assert!(!output.path().join("changed.jpg.tmp").exists());
+}
+
+#[tokio::test]
+async fn manifest_cache_revalidates_with_the_etag() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .and(header("if-none-match", "\"v1\""))
+        .respond_with(ResponseTemplate::new(304))
+        .expect(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"v1\"")
+                .set_body_json(manifest_json(&server, &["alpha"])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let client = reqwest::Client::new();
+
+    // A zero TTL always asks the server, which answers the second time with 304.
+    let mut cache = ManifestCache::new(Duration::ZERO);
+    cache.get_or_refresh(&server.uri(), &client).await.unwrap();
+    let manifest = cache.get_or_refresh(&server.uri(), &client).await.unwrap();
+
+    assert!(manifest.data.contains_key("alpha"));
+}
+
+#[tokio::test]
+async fn get_range_keeps_keys_between_the_bounds() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(manifest_json(&server, &["alpha", "beta", "delta", "gamma"])),
+        )
+        .mount(&server)
+        .await;
+
+    let manifest = Manifest::get_range(&server.uri(), "beta", "delta")
+        .await
+        .unwrap();
+
+    let mut keys: Vec<_> = manifest.data.keys().collect();
+    keys.sort();
+    assert_eq!(keys, ["beta", "delta"]);
+}
+
+#[tokio::test]
+async fn union_all_suffixes_keys_listed_by_several_domains() {
+    let first = MockServer::start().await;
+    let second = MockServer::start().await;
+    for (server, keys) in [(&first, ["alpha", "shared"]), (&second, ["beta", "shared"])] {
+        Mock::given(method("GET"))
+            .and(path(MANIFEST_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(server, &keys)))
+            .mount(server)
+            .await;
+    }
+    let domains = [first.uri(), second.uri()];
+    let domains: Vec<&str> = domains.iter().map(String::as_str).collect();
+
+    let manifest = Manifest::get_all_domains(&domains, MergeStrategy::UnionAll)
+        .await
+        .unwrap();
+
+    let mut keys: Vec<_> = manifest.data.keys().collect();
+    keys.sort();
+    assert_eq!(keys, ["alpha", "beta", "shared-0", "shared-1"]);
+    let url = manifest.data["shared-1"].dhd.as_deref().unwrap();
+    assert!(url.starts_with(&second.uri()));
+    assert!(
+        Manifest::get_all_domains(&domains, MergeStrategy::ErrorOnConflict)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn streamed_manifest_matches_the_buffered_one() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(manifest_json(&server, &["alpha", "beta", "gamma"])),
+        )
+        .mount(&server)
+        .await;
+    let client = reqwest::Client::new();
+
+    let streamed: HashMap<_, _> = Manifest::get_via_stream(&server.uri(), &client)
+        .try_collect()
+        .await
+        .unwrap();
+    let buffered = Manifest::get_with_client(&server.uri(), &client)
+        .await
+        .unwrap();
+
+    assert_eq!(streamed, buffered.data);
+}
+
+#[tokio::test]
+async fn a_streamed_manifest_keeps_its_version_and_only_the_selected_entries() {
+    let server = MockServer::start().await;
+    let mut manifest = manifest_json(&server, &["alpha", "beta"]);
+    manifest["version"] = json!(3);
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest))
+        .mount(&server)
+        .await;
+    let output = tempfile::tempdir().unwrap();
+
+    let manifest = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .stream_manifest(true)
+        .key_prefix(Some("al".to_string()))
+        .build()
+        .fetch_manifest()
+        .await
+        .unwrap();
+
+    assert_eq!(manifest.version, 3);
+    assert_eq!(manifest.data.keys().collect::<Vec<_>>(), ["alpha"]);
+}
+
+fn manifest_data() -> impl Strategy<Value = ManifestData> {
+    let field = || proptest::option::of(any::<String>());
+    (
+        (
+            field(),
+            field(),
+            field(),
+            field(),
+            field(),
+            field(),
+            field(),
+        ),
+        (
+            field(),
+            field(),
+            field(),
+            field(),
+            field(),
+            field(),
+            field(),
+            field(),
+        ),
+    )
+        .prop_map(
+            |((_as, am, dhd, dsd, e, fs, s), (wcl0, wcl1, wcl2, wcs0, wcs1, wcs2, wfs, wft))| {
+                ManifestData {
+                    _as,
+                    am,
+                    dhd,
+                    dsd,
+                    e,
+                    fs,
+                    s,
+                    wcl0,
+                    wcl1,
+                    wcl2,
+                    wcs0,
+                    wcs1,
+                    wcs2,
+                    wfs,
+                    wft,
+                }
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn manifest_data_survives_a_json_round_trip(data in manifest_data()) {
+        let json = serde_json::to_string(&data).unwrap();
+        let parsed: ManifestData = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(parsed, data);
+    }
+}
+
+#[tokio::test]
+async fn manifest_keys_are_written_sorted() {
+    let server = MockServer::start().await;
+    let manifest: Manifest =
+        serde_json::from_value(manifest_json(&server, &["gamma", "alpha", "beta"])).unwrap();
+
+    let mut keys = Vec::new();
+    manifest.serialize_keys_to_writer(&mut keys).unwrap();
+
+    assert_eq!(keys, br#"["alpha","beta","gamma"]"#);
+}
+
+#[test]
+fn shrink_to_fit_removes_entries_without_urls() {
+    let mut manifest: Manifest = serde_json::from_value(json!({
+        "version": 1,
+        "data": {
+            "banner": { "e": "https://example.com/banner.jpg" },
+            "empty": {},
+        },
+    }))
+    .unwrap();
+
+    assert_eq!(manifest.shrink_to_fit(), 1);
+    assert_eq!(manifest.data.keys().collect::<Vec<_>>(), ["banner"]);
+}
+
+#[tokio::test]
+async fn field_map_renames_non_standard_fields() {
+    let server = MockServer::start().await;
+    let url = format!("{}/content/renamed.jpg?fm=jpg", server.uri());
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "version": 1,
+            "data": { "renamed": { "high_def": url } },
+        })))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .field_map(FieldMap::from_json(r#"{"high_def":"dhd"}"#).unwrap())
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    let wallpaper = std::fs::read(output.path().join("renamed.jpg")).unwrap();
+    assert_eq!(wallpaper, WALLPAPER);
+}
+
+#[tokio::test]
+async fn output_template_lays_files_out_by_date() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["dated"])))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    AppBuilder::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .output_template(Some(OutputTemplate::new("{year}/{month}/{key}.{ext}")))
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    let today = chrono::Local::now().date_naive();
+    let expected = today.format("%Y/%m/dated.jpg").to_string();
+    let wallpaper = std::fs::read(output.path().join(expected)).unwrap();
+    assert_eq!(wallpaper, WALLPAPER);
+}
+
+#[tokio::test]
+async fn cloned_apps_run_concurrently() {
+    let mut servers = Vec::new();
+    for keys in [["first"], ["second"]] {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(MANIFEST_PATH))
+            .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &keys)))
+            .mount(&server)
+            .await;
+        mount_wallpapers(&server).await;
+        servers.push(server);
+    }
+
+    let outputs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+    let apps: Vec<_> = servers
+        .iter()
+        .zip(&outputs)
+        .map(|(server, output)| App::new(&server.uri(), output.path().to_str().unwrap(), 1))
+        .collect();
+    let runs: Vec<_> = apps
+        .iter()
+        .cloned()
+        .map(|app| tokio::spawn(async move { app.run().await }))
+        .collect();
+    for run in runs {
+        assert_eq!(run.await.unwrap().unwrap().downloaded, 1);
+    }
+
+    assert!(outputs[0].path().join("first.jpg").exists());
+    assert!(outputs[1].path().join("second.jpg").exists());
+}
+
+#[tokio::test]
+async fn single_worker_downloads_both_wallpapers() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["first", "second"])),
+        )
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run()
+        .await
+        .unwrap();
+
+    let mut files: Vec<_> = std::fs::read_dir(output.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        [
+            output.path().join("first.jpg"),
+            output.path().join("second.jpg")
+        ]
+    );
+    for file in files {
+        assert_eq!(std::fs::read(file).unwrap(), WALLPAPER);
+    }
+}
+
+#[tokio::test]
+async fn parked_adaptive_workers_are_not_counted_as_active() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["a", "b", "c"])),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(WALLPAPER)
+                .set_delay(Duration::from_millis(500)),
+        )
+        .mount(&server)
+        .await;
+    let output = tempfile::tempdir().unwrap();
+    let status_file = output.path().join(".status.json");
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .max_workers(Some(4))
+        .status_file(Some(status_file.clone()))
+        .status_interval(Duration::from_millis(20))
+        .build();
+
+    let run = tokio::spawn(async move { app.run().await });
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let status: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&status_file).unwrap()).unwrap();
+    run.await.unwrap().unwrap();
+
+    assert_eq!(status["state"], "running");
+    assert_eq!(status["active_workers"], 1);
+}
+
+#[tokio::test]
+async fn run_with_manifest_skips_the_manifest_request() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let manifest: Manifest =
+        serde_json::from_value(manifest_json(&server, &["first", "second"])).unwrap();
+
+    let output = tempfile::tempdir().unwrap();
+    let summary = App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run_with_manifest(manifest)
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 2);
+    assert_eq!(summary.total_bytes, 2 * WALLPAPER.len() as u64);
+}
+
+#[tokio::test]
+async fn the_extension_of_an_untyped_url_is_sniffed_from_the_content() {
+    let server = MockServer::start().await;
+    let png = b"\x89PNG\r\n\x1a\n not really the rest of a png";
+    Mock::given(method("GET"))
+        .and(path("/download/1"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(&png[..], "application/octet-stream"))
+        .mount(&server)
+        .await;
+    let manifest = Manifest::from_urls([format!("{}/download/1", server.uri())]);
+
+    let output = tempfile::tempdir().unwrap();
+    App::new(&server.uri(), output.path().to_str().unwrap(), 1)
+        .run_with_manifest(manifest)
+        .await
+        .unwrap();
+
+    assert!(output.path().join("0.png").exists());
+    assert!(!output.path().join("0.jpg").exists());
+}
+
+#[tokio::test]
+async fn a_sniffed_wallpaper_is_revalidated_with_its_etag() {
+    let server = MockServer::start().await;
+    let png = b"\x89PNG\r\n\x1a\n not really the rest of a png";
+    Mock::given(method("GET"))
+        .and(path("/download/1"))
+        .and(header("if-none-match", "\"v1\""))
+        .respond_with(ResponseTemplate::new(304))
+        .expect(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/download/1"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"v1\"")
+                .set_body_raw(&png[..], "application/octet-stream"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let manifest = || Manifest::from_urls([format!("{}/download/1", server.uri())]);
+    let output = tempfile::tempdir().unwrap();
+    let etag_cache = tempfile::tempdir().unwrap();
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .etag_cache_dir(Some(etag_cache.path().to_path_buf()))
+        .build();
+
+    app.run_with_manifest(manifest()).await.unwrap();
+    let summary = app.run_with_manifest(manifest()).await.unwrap();
+
+    assert_eq!(summary.downloaded, 0);
+    assert_eq!(std::fs::read(output.path().join("0.png")).unwrap(), png);
+}
+
+#[tokio::test]
+async fn a_changed_format_replaces_the_sniffed_wallpaper() {
+    let server = MockServer::start().await;
+    let png = b"\x89PNG\r\n\x1a\n not really the rest of a png";
+    let gif = b"GIF89a not really the rest of a gif";
+    Mock::given(method("GET"))
+        .and(path("/download/1"))
+        .and(header("if-none-match", "\"v1\""))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"v2\"")
+                .set_body_raw(&gif[..], "application/octet-stream"),
+        )
+        .expect(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/download/1"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("etag", "\"v1\"")
+                .set_body_raw(&png[..], "application/octet-stream"),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    let manifest = || Manifest::from_urls([format!("{}/download/1", server.uri())]);
+    let output = tempfile::tempdir().unwrap();
+    let etag_cache = tempfile::tempdir().unwrap();
+    let app = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .etag_cache_dir(Some(etag_cache.path().to_path_buf()))
+        .build();
+
+    app.run_with_manifest(manifest()).await.unwrap();
+    let summary = app.run_with_manifest(manifest()).await.unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+    assert_eq!(std::fs::read(output.path().join("0.gif")).unwrap(), gif);
+    assert!(!output.path().join("0.png").exists());
+}
+
+#[tokio::test]
+async fn headers_from_a_curl_command_are_sent() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .and(header("authorization", "Bearer secret"))
+        .and(header("cookie", "session=abc"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["only"])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .and(header("authorization", "Bearer secret"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let curl = format!(
+        "curl '{}/panels-api' \\\n  -H 'accept-encoding: gzip, br' \\\n  -H $'authorization: Bearer secret' \\\n  -b \"session=abc\"",
+        server.uri()
+    );
+
+    let output = tempfile::tempdir().unwrap();
+    App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .extra_headers(ExtraHeaders::from_curl_command(&curl).unwrap())
+        .build()
+        .run()
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn a_manifest_behind_a_session_cookie_is_fetched() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .and(header("cookie", "session=abc"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["only"])))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(401))
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let jar = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(
+        jar.path(),
+        "# Netscape HTTP Cookie File\n127.0.0.1\tFALSE\t/\tFALSE\t0\tsession\tabc\n",
+    )
+    .unwrap();
+
+    let output = tempfile::tempdir().unwrap();
+    let summary = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .import_curl_cookies(Some(jar.path().to_path_buf()))
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 1);
+}
+
+#[tokio::test]
+async fn the_cookie_file_is_read_and_saved_in_the_netscape_format() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .and(header("cookie", "session=abc"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("set-cookie", "token=xyz; Path=/; Max-Age=3600")
+                .set_body_json(manifest_json(&server, &["only"])),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+    let jar = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(
+        jar.path(),
+        "# Netscape HTTP Cookie File\n127.0.0.1\tFALSE\t/\tFALSE\t0\tsession\tabc\n",
+    )
+    .unwrap();
+
+    let output = tempfile::tempdir().unwrap();
+    App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .cookie_file(Some(jar.path().to_path_buf()))
+        .save_cookies(true)
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    let saved = std::fs::read_to_string(jar.path()).unwrap();
+    assert!(saved.starts_with("# Netscape HTTP Cookie File\n"));
+    assert!(saved.contains("127.0.0.1\tFALSE\t/\tFALSE\t0\tsession\tabc\n"));
+    let token = saved
+        .lines()
+        .find(|line| line.ends_with("\ttoken\txyz"))
+        .unwrap();
+    assert!(token.starts_with("127.0.0.1\tFALSE\t/\tFALSE\t"));
+}
+
+#[tokio::test]
+async fn cookies_from_a_netscape_jar_are_sent() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(ResponseTemplate::new(200).set_body_json(manifest_json(&server, &["only"])))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex("^/content/.*"))
+        .and(header("cookie", "session=abc"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(WALLPAPER))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let jar = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(
+        jar.path(),
+        "# Netscape HTTP Cookie File\n\
+         127.0.0.1\tFALSE\t/\tFALSE\t0\tsession\tabc\n\
+         127.0.0.1\tFALSE\t/\tFALSE\t1\texpired\tgone\n",
+    )
+    .unwrap();
+
+    let output = tempfile::tempdir().unwrap();
+    App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .import_curl_cookies(Some(jar.path().to_path_buf()))
+        .build()
+        .run()
+        .await
+        .unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_token_bucket_refills_up_to_a_one_second_burst() {
+    let mut bucket = TokenBucket::new(1000);
+
+    assert_eq!(bucket.reserve(1000), Duration::ZERO);
+    assert_eq!(bucket.reserve(500), Duration::from_millis(500));
+    tokio::time::advance(Duration::from_millis(250)).await;
+    // Half of the debt has been paid back.
+    assert_eq!(bucket.reserve(0), Duration::from_millis(250));
+    // Far longer than the debt, but the bucket holds no more than a second's worth.
+    tokio::time::advance(Duration::from_secs(10)).await;
+    assert_eq!(bucket.reserve(1000), Duration::ZERO);
+    assert_eq!(bucket.reserve(250), Duration::from_millis(250));
+}
+
+#[tokio::test(start_paused = true)]
+async fn acquiring_from_the_token_bucket_sleeps_off_the_debt() {
+    let mut bucket = TokenBucket::new(1000);
+    let started = tokio::time::Instant::now();
+
+    bucket.acquire(1000).await;
+    assert_eq!(started.elapsed(), Duration::ZERO);
+    bucket.acquire(1500).await;
+    assert_eq!(started.elapsed(), Duration::from_millis(1500));
+}
+
+/// A builder that passes validation, for the tests to break one setting of.
+fn valid_builder() -> AppBuilder {
+    AppBuilder::new("http://localhost:8080", "downloads", 1)
+}
+
+/// The problems `validate` reports for `builder`, which must be invalid.
+fn validation_errors(builder: AppBuilder) -> Vec<String> {
+    match builder.validate().unwrap_err().downcast::<PanelsError>() {
+        Ok(PanelsError::InvalidConfiguration(errors)) => errors,
+        other => panic!("expected an invalid configuration, got {:?}", other),
+    }
+}
+
+#[test]
+fn the_valid_builder_passes_validation() {
+    valid_builder().validate().unwrap();
+}
+
+#[test]
+fn validation_reports_every_problem_at_once() {
+    let builder = AppBuilder::new("", "downloads", 0);
+    assert_eq!(
+        validation_errors(builder),
+        [
+            "workers must be at least 1",
+            "the panels domain must not be empty"
+        ]
+    );
+}
+
+#[test]
+fn validation_rejects_zero_workers() {
+    let builder = AppBuilder::new("http://localhost:8080", "downloads", 0);
+    assert_eq!(validation_errors(builder), ["workers must be at least 1"]);
+}
+
+#[test]
+fn validation_rejects_an_empty_domain() {
+    let builder = AppBuilder::new(" ", "downloads", 1);
+    assert_eq!(
+        validation_errors(builder),
+        ["the panels domain must not be empty"]
+    );
+}
+
+#[test]
+fn validation_rejects_an_empty_output_directory() {
+    let builder = AppBuilder::new("http://localhost:8080", "", 1);
+    assert_eq!(
+        validation_errors(builder),
+        ["the output directory must not be empty"]
+    );
+}
+
+#[test]
+fn validation_rejects_an_output_directory_with_a_nul() {
+    let builder = AppBuilder::new("http://localhost:8080", "down\0loads", 1);
+    assert_eq!(
+        validation_errors(builder),
+        ["the output directory down\0loads contains the invalid character '\\0'"]
+    );
+}
+
+#[test]
+fn validation_rejects_saving_cookies_without_a_cookie_file() {
+    let builder = valid_builder().save_cookies(true);
+    assert_eq!(
+        validation_errors(builder),
+        ["saving cookies requires a cookie file"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_file_mode_beyond_7777() {
+    let builder = valid_builder().file_mode(Some(0o17777));
+    assert_eq!(
+        validation_errors(builder),
+        ["file mode 17777 has bits set outside 7777"]
+    );
+}
+
+#[test]
+fn validation_rejects_fewer_adaptive_workers_than_workers() {
+    let builder = AppBuilder::new("http://localhost:8080", "downloads", 4).max_workers(Some(2));
+    assert_eq!(
+        validation_errors(builder),
+        ["the maximum number of adaptive workers must not be below the number of workers"]
+    );
+}
+
+#[test]
+fn validation_rejects_zero_connections_per_host() {
+    let builder = valid_builder().max_connections_per_host(Some(0));
+    assert_eq!(
+        validation_errors(builder),
+        ["the connections per host must be at least 1"]
+    );
+}
+
+#[cfg(feature = "native-tls")]
+#[test]
+fn validation_rejects_native_tls_with_tls_1_3() {
+    let builder = valid_builder()
+        .native_tls(true)
+        .tls_min_version(panels_downloader::TlsVersion::V1_3);
+    assert_eq!(
+        validation_errors(builder),
+        ["the native TLS backend cannot require TLS 1.3, use rustls instead"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_zero_total_timeout() {
+    let builder = valid_builder().total_timeout(Some(Duration::ZERO));
+    assert_eq!(
+        validation_errors(builder),
+        ["the total timeout must be longer than zero"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_zero_status_interval() {
+    let builder = valid_builder().status_interval(Duration::ZERO);
+    assert_eq!(
+        validation_errors(builder),
+        ["the status interval must be longer than zero"]
+    );
+}
+
+#[test]
+fn validation_rejects_zero_parallel_writes() {
+    let builder = valid_builder().parallel_writes(Some(0));
+    assert_eq!(
+        validation_errors(builder),
+        ["parallel writes must be at least 1"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_zero_rate_limit() {
+    let builder = valid_builder().rate_limit(Some(0));
+    assert_eq!(
+        validation_errors(builder),
+        ["the rate limit must be at least 1 byte per second"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_zero_circuit_breaker_threshold() {
+    let builder = valid_builder().circuit_breaker_threshold(Some(0));
+    assert_eq!(
+        validation_errors(builder),
+        ["the circuit breaker threshold must be at least 1"]
+    );
+}
+
+#[test]
+fn validation_rejects_an_empty_manifest_shard() {
+    let builder = valid_builder().manifest_shards(vec!["media-1a".to_string(), " ".to_string()]);
+    assert_eq!(
+        validation_errors(builder),
+        ["manifest shard names must not be empty"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_manifest_url_with_shards() {
+    let builder = valid_builder()
+        .manifest_url(Some("/panels-api/data/20240916/media-1a-i-p~s".to_string()))
+        .manifest_shards(vec!["media-1a".to_string()]);
+    assert_eq!(
+        validation_errors(builder),
+        ["a manifest URL cannot be combined with manifest shards"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_manifest_url_that_is_neither_a_path_nor_a_url() {
+    let builder = valid_builder().manifest_url(Some("manifest.json".to_string()));
+    assert_eq!(
+        validation_errors(builder),
+        ["the manifest URL must be a path or an absolute URL"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_name_template_without_a_placeholder() {
+    let builder = valid_builder().naming_strategy(NamingStrategy::Template("wallpaper".into()));
+    assert_eq!(
+        validation_errors(builder),
+        ["the name template must contain {key} or {basename}"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_name_template_with_a_path_separator() {
+    let builder = valid_builder().naming_strategy(NamingStrategy::Template("a/{key}".into()));
+    assert_eq!(
+        validation_errors(builder),
+        ["the name template must not contain path separators"]
+    );
+}
+
+#[test]
+fn validation_rejects_an_output_template_without_the_key() {
+    let builder = valid_builder().output_template(Some(OutputTemplate::new("{year}/{ext}")));
+    assert_eq!(
+        validation_errors(builder),
+        ["the output template must contain {key}"]
+    );
+}
+
+#[test]
+fn validation_rejects_an_output_template_leaving_the_directory() {
+    let builder = valid_builder().output_template(Some(OutputTemplate::new("../{key}.{ext}")));
+    assert_eq!(
+        validation_errors(builder),
+        ["the output template must be a relative path without `..`"]
+    );
+}
+
+#[cfg(feature = "exif")]
+#[test]
+fn validation_rejects_tagging_without_flushing() {
+    let builder = valid_builder()
+        .xmp_tags(vec![
+            panels_downloader::XmpTag::parse("collection=autumn").unwrap()
+        ])
+        .flush_writes(false);
+    assert_eq!(
+        validation_errors(builder),
+        ["tagging files needs their writes flushed, so it cannot skip flushing"]
+    );
+}
+
+#[test]
+fn validation_rejects_a_field_map_on_a_streamed_manifest() {
+    let builder = valid_builder()
+        .stream_manifest(true)
+        .field_map(FieldMap::from_json(r#"{"high_def":"dhd"}"#).unwrap());
+    assert_eq!(
+        validation_errors(builder),
+        ["a streamed manifest cannot have its fields renamed"]
+    );
+}
+
+#[test]
+fn a_held_pid_file_cannot_be_locked_again() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("panels.pid");
+
+    let held = PidFile::acquire(&path).unwrap();
+    let error = PidFile::acquire(&path).err().unwrap();
+
+    let pid = std::process::id();
+    match error.downcast_ref::<PanelsError>() {
+        Some(PanelsError::AnotherInstanceRunning(Some(holder))) => assert_eq!(*holder, pid),
+        _ => panic!("expected another instance to be running, got {:?}", error),
+    }
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "Another instance (pid {}) is already running on this output directory",
+            pid
+        )
+    );
+    drop(held);
+    PidFile::acquire(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn wallpapers_with_the_same_content_are_stored_once() {
+    use std::os::unix::fs::MetadataExt;
+
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(MANIFEST_PATH))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(manifest_json(&server, &["first", "second", "third"])),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/content/third.jpg"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(&b"another wallpaper"[..]))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    mount_wallpapers(&server).await;
+
+    let output = tempfile::tempdir().unwrap();
+    let summary = App::builder(&server.uri(), output.path().to_str().unwrap(), 1)
+        .deduplicate(true)
+        .build()
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(summary.downloaded, 3);
+    let metadata = |name| std::fs::metadata(output.path().join(name)).unwrap();
+    let (first, second, third) = (
+        metadata("first.jpg"),
+        metadata("second.jpg"),
+        metadata("third.jpg"),
+    );
+    assert_eq!((first.dev(), first.ino()), (second.dev(), second.ino()));
+    assert_eq!(first.nlink(), 2);
+    assert_ne!(first.ino(), third.ino());
+    assert_eq!(third.nlink(), 1);
+    assert_eq!(
+        std::fs::read(output.path().join("second.jpg")).unwrap(),
+        WALLPAPER
+    );
+}